@@ -0,0 +1,126 @@
+use pgrx::*;
+use nng::Message;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use crate::shared_cache;
+use crate::CACHE_GUC;
+
+//  Per-transaction cache of read responses, keyed by the configured
+//  endpoints and the request payload, see key. With pgmer2.cache
+//  enabled, repeated reads in a transaction get the same result
+//  without a round trip. The cache is cleared at the end of the
+//  transaction and on every write.
+//
+//  Behind it is the shared cache, see shared_cache.rs, used when
+//  pgmer2.shared_cache_size is set.
+
+//  Responses are not cached beyond this size.
+const MAX_BYTES : usize = 64 * 1024 * 1024;
+
+thread_local! {
+  static RESPONSES : RefCell<HashMap<Vec<u8>, (String, Vec<u8>)>> = RefCell::new(HashMap::new());
+  static BYTES     : Cell<usize> = Cell::new(0);
+}
+
+pub fn enabled() -> bool {
+  CACHE_GUC.get() || shared_cache::active()
+}
+
+//  Read before sending a request, and pass to put.
+
+pub fn generation() -> u64 {
+  shared_cache::generation()
+}
+
+//  The configured endpoints, then the request payload, which encodes
+//  command id, context and arguments. The URL settings can be changed
+//  per session, so backends may talk to different services, and their
+//  responses must not be mixed up.
+
+fn key(payload : &[u8]) -> Vec<u8> {
+  let primary  = crate::service_url();
+  let replicas = crate::replica_urls();
+
+  let mut key = Vec::with_capacity(primary.len() + replicas.len() + payload.len() + 2);
+
+  key.extend_from_slice(primary.as_bytes());
+  key.push(0);
+  key.extend_from_slice(replicas.as_bytes());
+  key.push(0);
+  key.extend_from_slice(payload);
+
+  return key;
+}
+
+//  Returns the cached response with the URL that served it.
+
+pub fn get(payload : &[u8]) -> Option<(String, Message)> {
+  let local   = CACHE_GUC.get();
+  let payload = &key(payload)[..];
+
+  if local {
+    let cached = RESPONSES.with(|x| {
+      x.borrow()
+        .get(payload)
+        .map(|(url, response)| (url.clone(), Message::from(response.as_slice())))
+    });
+
+    if cached.is_some() {
+      return cached;
+    }
+  }
+
+  let (url, response) = shared_cache::get(payload)?;
+
+  //  Keep it for the rest of the transaction, even if the shared
+  //  entry is evicted or invalidated by another backend.
+  if local {
+    put_local(payload, &url, &response);
+  }
+
+  return Some((url, Message::from(response.as_slice())));
+}
+
+fn put_local(payload : &[u8], url : &str, response : &[u8]) {
+  let size = payload.len() + response.len();
+
+  if BYTES.get() + size > MAX_BYTES {
+    debug1!("pgmer2: response cache is full");
+    return;
+  }
+
+  let old = RESPONSES.with(|x| {
+    x.borrow_mut().insert(payload.to_vec(), (url.to_string(), response.to_vec()))
+  });
+
+  match old {
+    Some((_, old)) => BYTES.set(BYTES.get() + size - payload.len() - old.len()),
+    None           => BYTES.set(BYTES.get() + size),
+  }
+}
+
+pub fn put(payload : &[u8], url : &str, response : &Message, generation : u64) {
+  let payload = &key(payload)[..];
+
+  if CACHE_GUC.get() {
+    put_local(payload, url, response.as_slice());
+  }
+
+  shared_cache::put(payload, url, response.as_slice(), generation);
+}
+
+//  Clears the cache of this transaction.
+
+pub fn clear() {
+  RESPONSES.with(|x| x.borrow_mut().clear());
+  BYTES.set(0);
+}
+
+//  Clears the cache of this transaction and invalidates the shared
+//  cache, after a request that may change the graph.
+
+pub fn invalidate() {
+  clear();
+  shared_cache::invalidate();
+}
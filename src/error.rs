@@ -0,0 +1,135 @@
+use pgrx::{PgLogLevel, PgSqlErrorCode};
+use pgrx::pg_sys::panic::ErrorReport;
+use std::fmt;
+
+//  Errors of the connector. Each kind maps to its own SQLSTATE,
+//  so callers can tell an unreachable service from a timeout, bad
+//  arguments or an error reported by the service itself.
+
+#[derive(Debug)]
+pub enum MrError {
+  //  A required argument is null.
+  NullArgument(String),
+  //  An argument has an invalid value.
+  InvalidArgument(String),
+  //  No service URL is configured.
+  NoEndpoint,
+  //  Could not dial the service, or the connection broke.
+  Connection {
+    url     : String,
+    command : String,
+    error   : nng::Error,
+  },
+  //  No response within the timeout.
+  Timeout {
+    url          : String,
+    command      : String,
+    timeout_msec : Option<u64>,
+  },
+  //  The service returned an error.
+  Service {
+    url     : String,
+    command : String,
+    message : String,
+  },
+  //  A request can't be encoded, or a response can't be decoded.
+  Protocol(String),
+  //  Errors from Postgres, e.g. SPI.
+  Internal(String),
+}
+
+impl MrError {
+  pub fn sqlstate(&self) -> PgSqlErrorCode {
+    match self {
+      MrError::NullArgument(_)    => PgSqlErrorCode::ERRCODE_NULL_VALUE_NOT_ALLOWED,
+      MrError::InvalidArgument(_) => PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+      MrError::NoEndpoint         => PgSqlErrorCode::ERRCODE_CONNECTION_EXCEPTION,
+      MrError::Connection { .. }  => PgSqlErrorCode::ERRCODE_CONNECTION_FAILURE,
+      MrError::Timeout { .. }     => PgSqlErrorCode::ERRCODE_QUERY_CANCELED,
+      MrError::Service { .. }     => PgSqlErrorCode::ERRCODE_DATA_EXCEPTION,
+      MrError::Protocol(_)        => PgSqlErrorCode::ERRCODE_PROTOCOL_VIOLATION,
+      MrError::Internal(_)        => PgSqlErrorCode::ERRCODE_INTERNAL_ERROR,
+    }
+  }
+
+  pub fn detail(&self) -> Option<String> {
+    match self {
+      MrError::Connection { url, command, .. } |
+      MrError::Timeout    { url, command, .. } |
+      MrError::Service    { url, command, .. } =>
+        Some(format!("service URL: {}, command: {}", url, command)),
+      _ => None,
+    }
+  }
+
+  pub fn hint(&self) -> Option<String> {
+    match self {
+      MrError::NoEndpoint =>
+        Some("Set pgmer2.service_url.".to_string()),
+      MrError::Connection { .. } =>
+        Some("Check that the MeritRank service is running and pgmer2.service_url is correct.".to_string()),
+      MrError::Timeout { .. } =>
+        Some("Increase pgmer2.recv_timeout_ms, or the timeout_msec argument.".to_string()),
+      _ => None,
+    }
+  }
+
+  //  Raises the error in Postgres.
+  pub fn report(self) -> ! {
+    ErrorReport::from(self).report(PgLogLevel::ERROR);
+    unreachable!();
+  }
+}
+
+impl fmt::Display for MrError {
+  fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      MrError::NullArgument(name)              => write!(f, "{} should not be null", name),
+      MrError::InvalidArgument(s)              => write!(f, "{}", s),
+      MrError::NoEndpoint                      => write!(f, "no service URL configured"),
+      MrError::Connection { error, .. }        => write!(f, "service connection failed: {}", error),
+      MrError::Timeout { timeout_msec, .. }    => match timeout_msec {
+        Some(x) => write!(f, "service did not respond in {} ms", x),
+        None    => write!(f, "service did not respond"),
+      },
+      MrError::Service { message, .. }         => write!(f, "service error: {}", message),
+      MrError::Protocol(s)                     => write!(f, "protocol error: {}", s),
+      MrError::Internal(s)                     => write!(f, "{}", s),
+    }
+  }
+}
+
+impl std::error::Error for MrError {}
+
+impl From<MrError> for ErrorReport {
+  fn from(e : MrError) -> ErrorReport {
+    let mut report = ErrorReport::new(e.sqlstate(), format!("pgmer2: {}", e), "pgmer2");
+
+    if let Some(detail) = e.detail() {
+      report = report.set_detail(detail);
+    }
+    if let Some(hint) = e.hint() {
+      report = report.set_hint(hint);
+    }
+
+    return report;
+  }
+}
+
+impl From<rmp_serde::encode::Error> for MrError {
+  fn from(e : rmp_serde::encode::Error) -> MrError {
+    MrError::Protocol(e.to_string())
+  }
+}
+
+impl From<rmp_serde::decode::Error> for MrError {
+  fn from(e : rmp_serde::decode::Error) -> MrError {
+    MrError::Protocol(e.to_string())
+  }
+}
+
+impl From<pgrx::spi::Error> for MrError {
+  fn from(e : pgrx::spi::Error) -> MrError {
+    MrError::Internal(e.to_string())
+  }
+}
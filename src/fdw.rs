@@ -0,0 +1,1101 @@
+use pgrx::*;
+use std::ffi::{c_int, c_void, CStr, CString};
+use core::result::Result;
+use meritrank_service::protocol::*;
+
+use crate::error::MrError;
+use crate::{
+  Route,
+  request,
+  command_payload,
+  recv_timeout_msec,
+  scores_payload,
+  put_edge_payload,
+  delete_edge_payload,
+  delete_node_payload,
+  write_command,
+};
+
+//  ================================================================
+//
+//    SQL
+//
+//  ================================================================
+
+extension_sql!(r#"
+CREATE OR REPLACE FUNCTION mr_fdw_handler() RETURNS fdw_handler
+  AS 'MODULE_PATHNAME', 'mr_fdw_handler'
+  LANGUAGE C STRICT;
+
+DO $$
+BEGIN
+  IF NOT EXISTS (SELECT FROM pg_foreign_data_wrapper WHERE fdwname = 'pgmer2') THEN
+    CREATE FOREIGN DATA WRAPPER pgmer2 HANDLER mr_fdw_handler;
+  END IF;
+END
+$$;
+
+CREATE SERVER IF NOT EXISTS pgmer2 FOREIGN DATA WRAPPER pgmer2;
+
+CREATE FOREIGN TABLE IF NOT EXISTS mr_edges (
+  src     text,
+  dst     text,
+  weight  double precision,
+  context text
+) SERVER pgmer2 OPTIONS (object 'edges');
+
+CREATE FOREIGN TABLE IF NOT EXISTS mr_scores (
+  src     text,
+  dst     text,
+  score   double precision,
+  context text
+) SERVER pgmer2 OPTIONS (object 'scores');
+
+CREATE FOREIGN TABLE IF NOT EXISTS mr_nodes (
+  node    text,
+  context text
+) SERVER pgmer2 OPTIONS (object 'nodes');
+"#,
+  name = "fdw",
+);
+
+//  ================================================================
+//
+//    Tables and columns
+//
+//  ================================================================
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Object {
+  Edges,
+  Scores,
+  Nodes,
+}
+
+impl Object {
+  fn parse(s : &str) -> Option<Object> {
+    match s {
+      "edges"  => Some(Object::Edges),
+      "scores" => Some(Object::Scores),
+      "nodes"  => Some(Object::Nodes),
+      _        => None,
+    }
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      Object::Edges  => "edges",
+      Object::Scores => "scores",
+      Object::Nodes  => "nodes",
+    }
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Field {
+  Src,
+  Dst,
+  Node,
+  //  weight for edges, score for scores
+  Value,
+  Context,
+}
+
+fn field(object : Object, column : &str) -> Option<Field> {
+  match (object, column) {
+    (Object::Edges,  "src")     => Some(Field::Src),
+    (Object::Edges,  "dst")     => Some(Field::Dst),
+    (Object::Edges,  "weight")  => Some(Field::Value),
+    (Object::Scores, "src")     => Some(Field::Src),
+    (Object::Scores, "dst")     => Some(Field::Dst),
+    (Object::Scores, "score")   => Some(Field::Value),
+    (Object::Nodes,  "node")    => Some(Field::Node),
+    (_,              "context") => Some(Field::Context),
+    _                           => None,
+  }
+}
+
+//  Columns identifying a row for UPDATE and DELETE.
+fn is_key(object : Object, field : Field) -> bool {
+  match object {
+    Object::Edges  => field == Field::Src || field == Field::Dst || field == Field::Context,
+    Object::Nodes  => field == Field::Node || field == Field::Context,
+    Object::Scores => false,
+  }
+}
+
+unsafe fn table_object(relid : pg_sys::Oid) -> Object {
+  let table   = pg_sys::GetForeignTable(relid);
+  let options = PgList::<pg_sys::DefElem>::from_pg((*table).options);
+
+  for def in options.iter_ptr() {
+    if CStr::from_ptr((*def).defname).to_bytes() != b"object" {
+      continue;
+    }
+
+    let value = CStr::from_ptr(pg_sys::defGetString(def)).to_string_lossy().into_owned();
+
+    match Object::parse(&value) {
+      Some(x) => return x,
+      None    => error!("pgmer2: unknown object '{}', expected edges, scores or nodes", value),
+    }
+  }
+
+  error!("pgmer2: foreign table should have the object option");
+}
+
+//  Field for each attribute of the relation, None for dropped ones.
+unsafe fn columns(object : Object, desc : pg_sys::TupleDesc) -> Vec<Option<Field>> {
+  PgTupleDesc::from_pg_unchecked(desc)
+    .iter()
+    .map(|attr| {
+      if attr.is_dropped() {
+        return None;
+      }
+      match field(object, attr.name()) {
+        Some(x) => Some(x),
+        None    => error!("pgmer2: unknown column {} for {}", attr.name(), object.name()),
+      }
+    })
+    .collect()
+}
+
+//  ================================================================
+//
+//    Conditions
+//
+//  ================================================================
+
+#[derive(Clone, Debug)]
+enum Value {
+  Text(String),
+  Number(f64),
+}
+
+fn is_text_type(typ : pg_sys::Oid) -> bool {
+  typ == PgBuiltInOids::TEXTOID.value()    ||
+  typ == PgBuiltInOids::VARCHAROID.value() ||
+  typ == PgBuiltInOids::BPCHAROID.value()
+}
+
+fn is_number_type(typ : pg_sys::Oid) -> bool {
+  typ == PgBuiltInOids::FLOAT8OID.value()  ||
+  typ == PgBuiltInOids::FLOAT4OID.value()  ||
+  typ == PgBuiltInOids::INT2OID.value()    ||
+  typ == PgBuiltInOids::INT4OID.value()    ||
+  typ == PgBuiltInOids::INT8OID.value()    ||
+  typ == PgBuiltInOids::NUMERICOID.value()
+}
+
+unsafe fn to_value(datum : pg_sys::Datum, typ : pg_sys::Oid) -> Value {
+  let number = |x : Option<f64>| Value::Number(x.unwrap_or(f64::NAN));
+
+  if typ == PgBuiltInOids::FLOAT8OID.value() {
+    number(f64::from_datum(datum, false))
+  } else if typ == PgBuiltInOids::FLOAT4OID.value() {
+    number(f32::from_datum(datum, false).map(|x| x as f64))
+  } else if typ == PgBuiltInOids::INT2OID.value() {
+    number(i16::from_datum(datum, false).map(|x| x as f64))
+  } else if typ == PgBuiltInOids::INT4OID.value() {
+    number(i32::from_datum(datum, false).map(|x| x as f64))
+  } else if typ == PgBuiltInOids::INT8OID.value() {
+    number(i64::from_datum(datum, false).map(|x| x as f64))
+  } else if typ == PgBuiltInOids::NUMERICOID.value() {
+    number(direct_function_call::<f64>(pg_sys::numeric_float8, &[Some(datum)]))
+  } else {
+    Value::Text(String::from_datum(datum, false).unwrap_or_default())
+  }
+}
+
+fn commute(op : &str) -> &str {
+  match op {
+    "<"  => ">",
+    "<=" => ">=",
+    ">"  => "<",
+    ">=" => "<=",
+    x    => x,
+  }
+}
+
+//  Checks if a WHERE clause has form `column op expr`, where expr is
+//  a constant or a parameter, and the scan can enforce it exactly.
+//  The expression is evaluated when the scan starts.
+
+unsafe fn pushable_cond(
+  object : Object,
+  relid  : pg_sys::Oid,
+  varno  : pg_sys::Index,
+  clause : *mut pg_sys::Node,
+) -> Option<(String, String, *mut pg_sys::Node)> {
+  if !is_a(clause, pg_sys::NodeTag::T_OpExpr) {
+    return None;
+  }
+
+  let op   = clause as *mut pg_sys::OpExpr;
+  let args = PgList::<pg_sys::Node>::from_pg((*op).args);
+
+  if args.len() != 2 {
+    return None;
+  }
+
+  let strip = |x : *mut pg_sys::Node| {
+    if is_a(x, pg_sys::NodeTag::T_RelabelType) {
+      (*(x as *mut pg_sys::RelabelType)).arg as *mut pg_sys::Node
+    } else {
+      x
+    }
+  };
+
+  let left  = strip(args.get_ptr(0)?);
+  let right = strip(args.get_ptr(1)?);
+
+  let is_our_var = |x : *mut pg_sys::Node| {
+    is_a(x, pg_sys::NodeTag::T_Var) &&
+    (*(x as *mut pg_sys::Var)).varno as i64 == varno as i64 &&
+    (*(x as *mut pg_sys::Var)).varlevelsup == 0 &&
+    (*(x as *mut pg_sys::Var)).varattno > 0
+  };
+
+  let is_value = |x : *mut pg_sys::Node| {
+    is_a(x, pg_sys::NodeTag::T_Const) || is_a(x, pg_sys::NodeTag::T_Param)
+  };
+
+  let opname = pg_sys::get_opname((*op).opno);
+  if opname.is_null() {
+    return None;
+  }
+  let opname = CStr::from_ptr(opname).to_string_lossy().into_owned();
+
+  let (var, expr, opname) =
+    if is_our_var(left) && is_value(right) {
+      (left, right, opname)
+    } else if is_value(left) && is_our_var(right) {
+      (right, left, commute(&opname).to_string())
+    } else {
+      return None;
+    };
+
+  let attname = pg_sys::get_attname(relid, (*(var as *mut pg_sys::Var)).varattno, false);
+  let column  = CStr::from_ptr(attname).to_string_lossy().into_owned();
+  let typ     = pg_sys::exprType(expr);
+
+  let ok = match field(object, &column)? {
+    Field::Value => is_number_type(typ) && ["<", "<=", ">", ">="].contains(&opname.as_str()),
+    _            => is_text_type(typ)   && opname == "=",
+  };
+
+  if !ok {
+    return None;
+  }
+
+  return Some((column, opname, expr));
+}
+
+//  Conditions of a scan, with expressions evaluated.
+
+#[derive(Default, Debug)]
+struct Filter {
+  src     : Option<String>,
+  dst     : Option<String>,
+  node    : Option<String>,
+  context : Option<String>,
+  //  (bound, inclusive)
+  lower   : Option<(f64, bool)>,
+  upper   : Option<(f64, bool)>,
+  //  Set when conditions contradict each other or compare with null.
+  empty   : bool,
+}
+
+impl Filter {
+  fn set_text(slot : &mut Option<String>, value : String, empty : &mut bool) {
+    match slot {
+      Some(x) if *x != value => *empty = true,
+      _                      => *slot = Some(value),
+    }
+  }
+
+  fn apply(&mut self, field : Field, op : &str, value : Option<Value>) {
+    let value = match value {
+      Some(x) => x,
+      None    => {
+        self.empty = true;
+        return;
+      },
+    };
+
+    match (field, value) {
+      (Field::Src,     Value::Text(x)) => Filter::set_text(&mut self.src,     x, &mut self.empty),
+      (Field::Dst,     Value::Text(x)) => Filter::set_text(&mut self.dst,     x, &mut self.empty),
+      (Field::Node,    Value::Text(x)) => Filter::set_text(&mut self.node,    x, &mut self.empty),
+      (Field::Context, Value::Text(x)) => Filter::set_text(&mut self.context, x, &mut self.empty),
+
+      (Field::Value, Value::Number(x)) => {
+        let inclusive = op == "<=" || op == ">=";
+
+        //  Keep the tightest bound.
+        if op == ">" || op == ">=" {
+          match self.lower {
+            Some((y, _))     if y > x  => {},
+            Some((y, false)) if y == x => {},
+            _                          => self.lower = Some((x, inclusive)),
+          }
+        } else {
+          match self.upper {
+            Some((y, _))     if y < x  => {},
+            Some((y, false)) if y == x => {},
+            _                          => self.upper = Some((x, inclusive)),
+          }
+        }
+      },
+
+      _ => {},
+    }
+  }
+
+  fn matches(&self, row : &Row) -> bool {
+    let text = |want : &Option<String>, have : &Option<String>| {
+      match want {
+        Some(x) => have.as_ref() == Some(x),
+        None    => true,
+      }
+    };
+
+    let value = row.value.unwrap_or(f64::NAN);
+
+    let lower = match self.lower {
+      Some((x, true))  => value >= x,
+      Some((x, false)) => value >  x,
+      None             => true,
+    };
+
+    let upper = match self.upper {
+      Some((x, true))  => value <= x,
+      Some((x, false)) => value <  x,
+      None             => true,
+    };
+
+    text(&self.src,  &row.src)  &&
+    text(&self.dst,  &row.dst)  &&
+    text(&self.node, &row.node) &&
+    lower && upper
+  }
+}
+
+//  ================================================================
+//
+//    Scan
+//
+//  ================================================================
+
+#[derive(Default, Clone, Debug)]
+struct Row {
+  src     : Option<String>,
+  dst     : Option<String>,
+  node    : Option<String>,
+  context : Option<String>,
+  value   : Option<f64>,
+}
+
+struct ScanState {
+  object  : Object,
+  conds   : Vec<(String, String)>,
+  limit   : Option<i64>,
+  exprs   : *mut pg_sys::List,
+  columns : Vec<Option<Field>>,
+  rows    : Option<Vec<Row>>,
+  next    : usize,
+}
+
+fn fetch(
+  object : Object,
+  filter : &Filter,
+  limit  : Option<i64>,
+) -> Result<Vec<Row>, MrError> {
+  if filter.empty {
+    return Ok(vec![]);
+  }
+
+  let context = filter.context.clone().unwrap_or("".to_string());
+
+  let rows : Vec<Row> = match object {
+    Object::Edges => {
+      let payload = command_payload(CMD_EDGES, &context, true, &())?;
+
+      let response : Vec<(String, String, f64)> =
+        request(Route::Replica, payload, Some(recv_timeout_msec()))?;
+
+      response
+        .into_iter()
+        .map(|(src, dst, weight)| Row {
+          src     : Some(src),
+          dst     : Some(dst),
+          value   : Some(weight),
+          context : Some(context.clone()),
+          ..Row::default()
+        })
+        .collect()
+    },
+
+    Object::Scores => {
+      let src = match filter.src.as_deref() {
+        Some(x) => x,
+        None    => return Err(MrError::InvalidArgument("mr_scores requires a src = ... condition".to_string())),
+      };
+
+      let payload = match filter.dst.as_deref() {
+        Some(dst) => command_payload(CMD_NODE_SCORE, &context, true, &(src, dst))?,
+
+        None => {
+          let bound = |b : Option<(f64, bool)>, inclusive : bool| {
+            match b {
+              Some((x, i)) if i == inclusive => Some(x),
+              _                              => None,
+            }
+          };
+
+          let count = limit.map(|x| x.min(i32::MAX as i64) as i32);
+
+          scores_payload(
+            Some(&context),
+            Some(src),
+            Some(false),
+            None,
+            bound(filter.upper, false), bound(filter.upper, true),
+            bound(filter.lower, false), bound(filter.lower, true),
+            Some(0),
+            Some(count.unwrap_or(i32::MAX)),
+            None,
+            None,
+            None
+          )?
+        },
+      };
+
+      let response : Vec<(String, String, f64)> =
+        request(Route::Replica, payload, Some(recv_timeout_msec()))?;
+
+      response
+        .into_iter()
+        .map(|(src, dst, score)| Row {
+          src     : Some(src),
+          dst     : Some(dst),
+          value   : Some(score),
+          context : Some(context.clone()),
+          ..Row::default()
+        })
+        .collect()
+    },
+
+    Object::Nodes => {
+      let payload = command_payload(CMD_NODE_LIST, &context, true, &())?;
+
+      let response : Vec<(String,)> =
+        request(Route::Replica, payload, Some(recv_timeout_msec()))?;
+
+      response
+        .into_iter()
+        .map(|(node,)| Row {
+          node    : Some(node),
+          context : Some(context.clone()),
+          ..Row::default()
+        })
+        .collect()
+    },
+  };
+
+  return Ok(rows.into_iter().filter(|x| filter.matches(x)).collect());
+}
+
+#[pg_guard]
+unsafe extern "C" fn get_foreign_rel_size(
+  _root     : *mut pg_sys::PlannerInfo,
+  baserel   : *mut pg_sys::RelOptInfo,
+  relid     : pg_sys::Oid,
+) {
+  (*baserel).rows = match table_object(relid) {
+    Object::Scores => 100.0,
+    _              => 1000.0,
+  };
+}
+
+#[pg_guard]
+unsafe extern "C" fn get_foreign_paths(
+  root    : *mut pg_sys::PlannerInfo,
+  baserel : *mut pg_sys::RelOptInfo,
+  _relid  : pg_sys::Oid,
+) {
+  let rows = (*baserel).rows;
+
+  let path = pg_sys::create_foreignscan_path(
+    root,
+    baserel,
+    std::ptr::null_mut(),
+    rows,
+    10.0,
+    10.0 + rows,
+    std::ptr::null_mut(),
+    std::ptr::null_mut(),
+    std::ptr::null_mut(),
+    std::ptr::null_mut(),
+  );
+
+  pg_sys::add_path(baserel, path as *mut pg_sys::Path);
+}
+
+#[pg_guard]
+unsafe extern "C" fn get_foreign_plan(
+  root         : *mut pg_sys::PlannerInfo,
+  baserel      : *mut pg_sys::RelOptInfo,
+  relid        : pg_sys::Oid,
+  _best_path   : *mut pg_sys::ForeignPath,
+  tlist        : *mut pg_sys::List,
+  scan_clauses : *mut pg_sys::List,
+  outer_plan   : *mut pg_sys::Plan,
+) -> *mut pg_sys::ForeignScan {
+  let object = table_object(relid);
+
+  let mut conds      = vec![];
+  let mut exprs      = PgList::<pg_sys::Node>::new();
+  let mut all_pushed = true;
+
+  for rinfo in PgList::<pg_sys::RestrictInfo>::from_pg(scan_clauses).iter_ptr() {
+    match pushable_cond(object, relid, (*baserel).relid, (*rinfo).clause as *mut pg_sys::Node) {
+      Some((column, op, expr)) => {
+        conds.push((column, op));
+        exprs.push(expr);
+      },
+      None => all_pushed = false,
+    }
+  }
+
+  //  LIMIT can be passed to the service only when it is the last step:
+  //  no joins, no ORDER BY, and every condition is enforced by the scan.
+  let parse  = (*root).parse;
+  let single = pg_sys::bms_membership((*root).all_baserels) == pg_sys::BMS_Membership_BMS_SINGLETON;
+
+  let limit =
+    if object == Object::Scores &&
+       all_pushed &&
+       single &&
+       (*parse).sortClause.is_null() &&
+       (*root).limit_tuples > 0.0 {
+      Some((*root).limit_tuples as i64)
+    } else {
+      None
+    };
+
+  //  All conditions are still checked by the executor as well.
+  let local = pg_sys::extract_actual_clauses(scan_clauses, false);
+
+  let private = match rmp_serde::to_vec(&(object.name(), &conds, limit)) {
+    Ok(x)  => x,
+    Err(e) => error!("pgmer2: {}", e),
+  };
+
+  let private = pg_sys::makeConst(
+    PgBuiltInOids::BYTEAOID.value(),
+    -1,
+    pg_sys::InvalidOid,
+    -1,
+    private.into_datum().unwrap(),
+    false,
+    false,
+  );
+
+  let mut fdw_private = PgList::<pg_sys::Const>::new();
+  fdw_private.push(private);
+
+  return pg_sys::make_foreignscan(
+    tlist,
+    local,
+    (*baserel).relid,
+    exprs.into_pg(),
+    fdw_private.into_pg(),
+    std::ptr::null_mut(),
+    std::ptr::null_mut(),
+    outer_plan,
+  );
+}
+
+#[pg_guard]
+unsafe extern "C" fn begin_foreign_scan(
+  node    : *mut pg_sys::ForeignScanState,
+  _eflags : c_int,
+) {
+  let plan    = (*node).ss.ps.plan as *mut pg_sys::ForeignScan;
+  let private = PgList::<pg_sys::Const>::from_pg((*plan).fdw_private);
+
+  let bytes = match private.get_ptr(0) {
+    Some(c) => Vec::<u8>::from_datum((*c).constvalue, false).unwrap_or_default(),
+    None    => error!("pgmer2: foreign scan has no private data"),
+  };
+
+  let (object, conds, limit) : (String, Vec<(String, String)>, Option<i64>) =
+    match rmp_serde::from_slice(&bytes) {
+      Ok(x)  => x,
+      Err(e) => error!("pgmer2: {}", e),
+    };
+
+  let object = match Object::parse(&object) {
+    Some(x) => x,
+    None    => error!("pgmer2: unknown object '{}'", object),
+  };
+
+  let rel = (*node).ss.ss_currentRelation;
+
+  let state = ScanState {
+    object,
+    conds,
+    limit,
+    exprs   : pg_sys::ExecInitExprList((*plan).fdw_exprs, node as *mut pg_sys::PlanState),
+    columns : columns(object, (*rel).rd_att),
+    rows    : None,
+    next    : 0,
+  };
+
+  (*node).fdw_state =
+    PgMemoryContexts::CurrentMemoryContext.leak_and_drop_on_delete(state) as *mut c_void;
+}
+
+unsafe fn scan_filter(
+  node  : *mut pg_sys::ForeignScanState,
+  state : &ScanState,
+) -> Filter {
+  let plan     = (*node).ss.ps.plan as *mut pg_sys::ForeignScan;
+  let econtext = (*node).ss.ps.ps_ExprContext;
+  let exprs    = PgList::<pg_sys::Node>::from_pg((*plan).fdw_exprs);
+  let states   = PgList::<pg_sys::ExprState>::from_pg(state.exprs);
+
+  let mut filter = Filter::default();
+
+  for (n, (column, op)) in state.conds.iter().enumerate() {
+    let (expr, expr_state) = match (exprs.get_ptr(n), states.get_ptr(n)) {
+      (Some(x), Some(y)) => (x, y),
+      _                  => error!("pgmer2: foreign scan expressions mismatch"),
+    };
+
+    let eval = match (*expr_state).evalfunc {
+      Some(f) => f,
+      None    => error!("pgmer2: expression is not initialized"),
+    };
+
+    let mut is_null = false;
+    let datum       = eval(expr_state, econtext, &mut is_null);
+
+    let value =
+      if is_null {
+        None
+      } else {
+        Some(to_value(datum, pg_sys::exprType(expr)))
+      };
+
+    match field(state.object, column) {
+      Some(f) => filter.apply(f, op, value),
+      None    => {},
+    }
+  }
+
+  return filter;
+}
+
+unsafe fn clear_slot(slot : *mut pg_sys::TupleTableSlot) {
+  match (*(*slot).tts_ops).clear {
+    Some(clear) => clear(slot),
+    None        => {},
+  }
+}
+
+#[pg_guard]
+unsafe extern "C" fn iterate_foreign_scan(
+  node : *mut pg_sys::ForeignScanState,
+) -> *mut pg_sys::TupleTableSlot {
+  let state = &mut *((*node).fdw_state as *mut ScanState);
+  let slot  = (*node).ss.ss_ScanTupleSlot;
+
+  clear_slot(slot);
+
+  if state.rows.is_none() {
+    let filter = scan_filter(node, state);
+
+    match fetch(state.object, &filter, state.limit) {
+      Ok(rows) => state.rows = Some(rows),
+      Err(e)   => e.report(),
+    }
+    state.next = 0;
+  }
+
+  let row = match state.rows.as_ref().and_then(|x| x.get(state.next)) {
+    Some(x) => x.clone(),
+    None    => return slot,
+  };
+
+  state.next += 1;
+
+  let natts  = state.columns.len();
+  let values = std::slice::from_raw_parts_mut((*slot).tts_values, natts);
+  let nulls  = std::slice::from_raw_parts_mut((*slot).tts_isnull, natts);
+
+  for (n, column) in state.columns.iter().enumerate() {
+    let datum = match column {
+      Some(Field::Src)     => row.src    .clone().into_datum(),
+      Some(Field::Dst)     => row.dst    .clone().into_datum(),
+      Some(Field::Node)    => row.node   .clone().into_datum(),
+      Some(Field::Context) => row.context.clone().into_datum(),
+      Some(Field::Value)   => row.value         .into_datum(),
+      None                 => None,
+    };
+
+    match datum {
+      Some(x) => {
+        values[n] = x;
+        nulls[n]  = false;
+      },
+      None => {
+        values[n] = pg_sys::Datum::from(0);
+        nulls[n]  = true;
+      },
+    }
+  }
+
+  pg_sys::ExecStoreVirtualTuple(slot);
+  return slot;
+}
+
+#[pg_guard]
+unsafe extern "C" fn rescan_foreign_scan(node : *mut pg_sys::ForeignScanState) {
+  let state = &mut *((*node).fdw_state as *mut ScanState);
+
+  //  Parameters may have changed, so fetch again.
+  state.rows = None;
+  state.next = 0;
+}
+
+#[pg_guard]
+unsafe extern "C" fn end_foreign_scan(_node : *mut pg_sys::ForeignScanState) {
+  //  The state is dropped with the executor memory context.
+}
+
+//  ================================================================
+//
+//    Modify
+//
+//  ================================================================
+
+struct ModifyState {
+  object  : Object,
+  columns : Vec<Option<Field>>,
+  //  Junk attributes holding the old key values.
+  keys    : Vec<(Field, pg_sys::AttrNumber)>,
+}
+
+fn junk_name(column : &str) -> CString {
+  CString::new(format!("mr_{}", column)).unwrap()
+}
+
+unsafe fn slot_datum(
+  slot  : *mut pg_sys::TupleTableSlot,
+  attno : pg_sys::AttrNumber,
+) -> Option<pg_sys::Datum> {
+  if (*slot).tts_nvalid < attno {
+    pg_sys::slot_getsomeattrs_int(slot, attno as c_int);
+  }
+
+  let n = (attno - 1) as usize;
+
+  if *(*slot).tts_isnull.add(n) {
+    None
+  } else {
+    Some(*(*slot).tts_values.add(n))
+  }
+}
+
+unsafe fn set_row_field(row : &mut Row, field : Field, datum : Option<pg_sys::Datum>) {
+  let text = || datum.and_then(|x| String::from_datum(x, false));
+
+  match field {
+    Field::Src     => row.src     = text(),
+    Field::Dst     => row.dst     = text(),
+    Field::Node    => row.node    = text(),
+    Field::Context => row.context = text(),
+    Field::Value   => row.value   = datum.and_then(|x| f64::from_datum(x, false)),
+  }
+}
+
+unsafe fn new_row(state : &ModifyState, slot : *mut pg_sys::TupleTableSlot) -> Row {
+  let mut row = Row::default();
+
+  for (n, column) in state.columns.iter().enumerate() {
+    match column {
+      Some(f) => set_row_field(&mut row, *f, slot_datum(slot, (n + 1) as pg_sys::AttrNumber)),
+      None    => {},
+    }
+  }
+
+  return row;
+}
+
+unsafe fn old_row(state : &ModifyState, plan_slot : *mut pg_sys::TupleTableSlot) -> Row {
+  let mut row = Row::default();
+
+  for (f, attno) in state.keys.iter() {
+    set_row_field(&mut row, *f, slot_datum(plan_slot, *attno));
+  }
+
+  return row;
+}
+
+fn put_edge(row : &Row) -> Result<(), MrError> {
+  match (row.src.as_deref(), row.dst.as_deref(), row.value) {
+    (Some(src), Some(dst), Some(weight)) => {
+      let context = row.context.as_deref().unwrap_or("");
+      write_command(put_edge_payload(context, src, dst, weight)?)
+    },
+    _ => Err(MrError::NullArgument("src, dst and weight".to_string())),
+  }
+}
+
+fn delete_edge(row : &Row) -> Result<(), MrError> {
+  match (row.src.as_deref(), row.dst.as_deref()) {
+    (Some(src), Some(dst)) => {
+      let context = row.context.as_deref().unwrap_or("");
+      write_command(delete_edge_payload(context, src, dst)?)
+    },
+    _ => Ok(()),
+  }
+}
+
+fn delete_node(row : &Row) -> Result<(), MrError> {
+  match row.node.as_deref() {
+    Some(node) => {
+      let context = row.context.as_deref().unwrap_or("");
+      write_command(delete_node_payload(context, node)?)
+    },
+    None => Ok(()),
+  }
+}
+
+#[pg_guard]
+unsafe extern "C" fn add_foreign_update_targets(
+  root        : *mut pg_sys::PlannerInfo,
+  rtindex     : pg_sys::Index,
+  _target_rte : *mut pg_sys::RangeTblEntry,
+  target_rel  : pg_sys::Relation,
+) {
+  let object = table_object((*target_rel).rd_id);
+
+  for (n, attr) in PgTupleDesc::from_pg_unchecked((*target_rel).rd_att).iter().enumerate() {
+    if attr.is_dropped() {
+      continue;
+    }
+
+    match field(object, attr.name()) {
+      Some(f) if is_key(object, f) => {
+        let var = pg_sys::makeVar(
+          rtindex as _,
+          (n + 1) as pg_sys::AttrNumber,
+          attr.atttypid,
+          attr.atttypmod,
+          attr.attcollation,
+          0,
+        );
+        let name = junk_name(attr.name());
+        pg_sys::add_row_identity_var(root, var, rtindex, pg_sys::pstrdup(name.as_ptr()));
+      },
+      _ => {},
+    }
+  }
+}
+
+#[pg_guard]
+unsafe extern "C" fn plan_foreign_modify(
+  _root          : *mut pg_sys::PlannerInfo,
+  _plan          : *mut pg_sys::ModifyTable,
+  _result_rel    : pg_sys::Index,
+  _subplan_index : c_int,
+) -> *mut pg_sys::List {
+  std::ptr::null_mut()
+}
+
+#[pg_guard]
+unsafe extern "C" fn begin_foreign_modify(
+  mtstate        : *mut pg_sys::ModifyTableState,
+  rinfo          : *mut pg_sys::ResultRelInfo,
+  _fdw_private   : *mut pg_sys::List,
+  _subplan_index : c_int,
+  eflags         : c_int,
+) {
+  if eflags & (pg_sys::EXEC_FLAG_EXPLAIN_ONLY as c_int) != 0 {
+    return;
+  }
+
+  let rel    = (*rinfo).ri_RelationDesc;
+  let object = table_object((*rel).rd_id);
+
+  let mut keys = vec![];
+
+  if (*mtstate).operation != pg_sys::CmdType_CMD_INSERT {
+    let subplan = (*(*mtstate).ps.lefttree).plan;
+
+    for attr in PgTupleDesc::from_pg_unchecked((*rel).rd_att).iter() {
+      if attr.is_dropped() {
+        continue;
+      }
+
+      match field(object, attr.name()) {
+        Some(f) if is_key(object, f) => {
+          let name  = junk_name(attr.name());
+          let attno = pg_sys::ExecFindJunkAttributeInTlist((*subplan).targetlist, name.as_ptr());
+
+          if attno == 0 {
+            error!("pgmer2: could not find junk {} column", attr.name());
+          }
+
+          keys.push((f, attno));
+        },
+        _ => {},
+      }
+    }
+  }
+
+  let state = ModifyState {
+    object,
+    columns : columns(object, (*rel).rd_att),
+    keys,
+  };
+
+  (*rinfo).ri_FdwState =
+    PgMemoryContexts::CurrentMemoryContext.leak_and_drop_on_delete(state) as *mut c_void;
+}
+
+#[pg_guard]
+unsafe extern "C" fn exec_foreign_insert(
+  _estate    : *mut pg_sys::EState,
+  rinfo      : *mut pg_sys::ResultRelInfo,
+  slot       : *mut pg_sys::TupleTableSlot,
+  _plan_slot : *mut pg_sys::TupleTableSlot,
+) -> *mut pg_sys::TupleTableSlot {
+  let state = &*((*rinfo).ri_FdwState as *mut ModifyState);
+
+  let res = match state.object {
+    Object::Edges => put_edge(&new_row(state, slot)),
+    _             => Err(MrError::InvalidArgument(format!("cannot insert into {}", state.object.name()))),
+  };
+
+  match res {
+    Ok(_)  => slot,
+    Err(e) => e.report(),
+  }
+}
+
+#[pg_guard]
+unsafe extern "C" fn exec_foreign_update(
+  _estate   : *mut pg_sys::EState,
+  rinfo     : *mut pg_sys::ResultRelInfo,
+  slot      : *mut pg_sys::TupleTableSlot,
+  plan_slot : *mut pg_sys::TupleTableSlot,
+) -> *mut pg_sys::TupleTableSlot {
+  let state = &*((*rinfo).ri_FdwState as *mut ModifyState);
+
+  let res = match state.object {
+    Object::Edges => {
+      let old = old_row(state, plan_slot);
+      let new = new_row(state, slot);
+
+      let same_key =
+        (&old.src, &old.dst, old.context.as_deref().unwrap_or("")) ==
+        (&new.src, &new.dst, new.context.as_deref().unwrap_or(""));
+
+      if same_key {
+        put_edge(&new)
+      } else {
+        delete_edge(&old).and_then(|_| put_edge(&new))
+      }
+    },
+    _ => Err(MrError::InvalidArgument(format!("cannot update {}", state.object.name()))),
+  };
+
+  match res {
+    Ok(_)  => slot,
+    Err(e) => e.report(),
+  }
+}
+
+#[pg_guard]
+unsafe extern "C" fn exec_foreign_delete(
+  _estate   : *mut pg_sys::EState,
+  rinfo     : *mut pg_sys::ResultRelInfo,
+  slot      : *mut pg_sys::TupleTableSlot,
+  plan_slot : *mut pg_sys::TupleTableSlot,
+) -> *mut pg_sys::TupleTableSlot {
+  let state = &*((*rinfo).ri_FdwState as *mut ModifyState);
+  let old   = old_row(state, plan_slot);
+
+  let res = match state.object {
+    Object::Edges  => delete_edge(&old),
+    Object::Nodes  => delete_node(&old),
+    Object::Scores => Err(MrError::InvalidArgument("cannot delete from scores".to_string())),
+  };
+
+  match res {
+    Ok(_)  => slot,
+    Err(e) => e.report(),
+  }
+}
+
+#[pg_guard]
+unsafe extern "C" fn end_foreign_modify(
+  _estate : *mut pg_sys::EState,
+  _rinfo  : *mut pg_sys::ResultRelInfo,
+) {
+  //  The state is dropped with the executor memory context.
+}
+
+#[pg_guard]
+unsafe extern "C" fn is_foreign_rel_updatable(rel : pg_sys::Relation) -> c_int {
+  let insert = 1 << pg_sys::CmdType_CMD_INSERT;
+  let update = 1 << pg_sys::CmdType_CMD_UPDATE;
+  let delete = 1 << pg_sys::CmdType_CMD_DELETE;
+
+  match table_object((*rel).rd_id) {
+    Object::Edges  => (insert | update | delete) as c_int,
+    Object::Nodes  => delete as c_int,
+    Object::Scores => 0,
+  }
+}
+
+//  ================================================================
+//
+//    Handler
+//
+//  ================================================================
+
+#[no_mangle]
+pub extern "C" fn pg_finfo_mr_fdw_handler() -> &'static pg_sys::Pg_finfo_record {
+  const V1 : pg_sys::Pg_finfo_record = pg_sys::Pg_finfo_record { api_version : 1 };
+  &V1
+}
+
+#[no_mangle]
+#[pg_guard]
+pub unsafe extern "C" fn mr_fdw_handler(_fcinfo : pg_sys::FunctionCallInfo) -> pg_sys::Datum {
+  let mut routine = PgBox::<pg_sys::FdwRoutine>::alloc_node(pg_sys::NodeTag::T_FdwRoutine);
+
+  routine.GetForeignRelSize       = Some(get_foreign_rel_size);
+  routine.GetForeignPaths         = Some(get_foreign_paths);
+  routine.GetForeignPlan          = Some(get_foreign_plan);
+  routine.BeginForeignScan        = Some(begin_foreign_scan);
+  routine.IterateForeignScan      = Some(iterate_foreign_scan);
+  routine.ReScanForeignScan       = Some(rescan_foreign_scan);
+  routine.EndForeignScan          = Some(end_foreign_scan);
+
+  routine.AddForeignUpdateTargets = Some(add_foreign_update_targets);
+  routine.PlanForeignModify       = Some(plan_foreign_modify);
+  routine.BeginForeignModify      = Some(begin_foreign_modify);
+  routine.ExecForeignInsert       = Some(exec_foreign_insert);
+  routine.ExecForeignUpdate       = Some(exec_foreign_update);
+  routine.ExecForeignDelete       = Some(exec_foreign_delete);
+  routine.EndForeignModify        = Some(end_foreign_modify);
+  routine.IsForeignRelUpdatable   = Some(is_foreign_rel_updatable);
+
+  pg_sys::Datum::from(routine.into_pg())
+}
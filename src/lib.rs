@@ -1,37 +1,238 @@
 use lazy_static::lazy_static;
 use nng::*;
-use nng::options::{Options, RecvTimeout};
 use std::time::Duration;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::ffi::CStr;
 use pgrx::*;
-use pgrx::iter::SetOfIterator;
+use pgrx::iter::{SetOfIterator, TableIterator};
 use pgrx::prelude::PgHeapTuple;
-use serde::de::Deserialize;
+use pgrx::trigger_support::PgTrigger;
+use pgrx::bgworkers::{BackgroundWorker, BackgroundWorkerBuilder, SignalWakeFlags};
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::pgrx_sql_entity_graph::metadata::{
+  ArgumentError,
+  Returns,
+  ReturnsError,
+  SqlMapping,
+  SqlTranslatable,
+};
+use serde::de::{Deserialize, IgnoredAny};
+use serde::ser::Serialize;
 use std::env::var;
-use std::error::Error;
 use core::result::Result;
 use meritrank_service::protocol::*;
 
+mod cache;
+mod error;
+mod fdw;
+mod outbox;
+mod shared_cache;
+
+use error::MrError;
+
 #[cfg(any(test, feature = "pg_test"))]
 pub mod testing;
 
 pg_module_magic!();
 
 lazy_static! {
-  static ref SERVICE_URL : String =
+  static ref ENV_SERVICE_URL : String =
     var("MERITRANK_SERVICE_URL").unwrap_or("tcp://127.0.0.1:10234".to_string());
 
-  static ref RECV_TIMEOUT_MSEC : u64 =
+  static ref ENV_REPLICA_URLS : String =
+    var("MERITRANK_REPLICA_URLS").unwrap_or("".to_string());
+
+  static ref ENV_RECV_TIMEOUT_MSEC : u64 =
     var("MERITRANK_RECV_TIMEOUT_MSEC")
       .ok()
       .and_then(|s| s.parse::<u64>().ok())
       .unwrap_or(10000);
+
+  static ref ENV_SEND_TIMEOUT_MSEC : Option<u64> =
+    var("MERITRANK_SEND_TIMEOUT_MSEC")
+      .ok()
+      .and_then(|s| s.parse::<u64>().ok());
 }
 
+//  GUCs. Unset values fall back to the environment variables above.
+
+static SERVICE_URL_GUC : GucSetting<Option<&'static CStr>> =
+  GucSetting::<Option<&'static CStr>>::new(None);
+
+static REPLICA_URLS_GUC : GucSetting<Option<&'static CStr>> =
+  GucSetting::<Option<&'static CStr>>::new(None);
+
+static RECV_TIMEOUT_GUC : GucSetting<i32> = GucSetting::<i32>::new(-1);
+static TRANSACTIONAL_GUC : GucSetting<bool> = GucSetting::<bool>::new(false);
+static SEND_TIMEOUT_GUC : GucSetting<i32> = GucSetting::<i32>::new(-1);
+static OUTBOX_GUC : GucSetting<bool> = GucSetting::<bool>::new(false);
+static OUTBOX_NAPTIME_GUC : GucSetting<i32> = GucSetting::<i32>::new(1000);
+static PAGE_SIZE_GUC : GucSetting<i32> = GucSetting::<i32>::new(10000);
+static PARALLELISM_GUC : GucSetting<i32> = GucSetting::<i32>::new(64);
+static CACHE_GUC : GucSetting<bool> = GucSetting::<bool>::new(false);
+static SHARED_CACHE_SIZE_GUC : GucSetting<i32> = GucSetting::<i32>::new(0);
+static SHARED_CACHE_TTL_GUC : GucSetting<i32> = GucSetting::<i32>::new(10000);
+
+static OUTBOX_DATABASE_GUC : GucSetting<Option<&'static CStr>> =
+  GucSetting::<Option<&'static CStr>>::new(None);
+
 const VERSION : &str = match option_env!("CARGO_PKG_VERSION") {
   Some(x) => x,
   None    => "dev"
 };
 
+//  ================================================================
+//
+//    Init
+//
+//  ================================================================
+
+#[pg_guard]
+pub extern "C" fn _PG_init() {
+  GucRegistry::define_string_guc(
+    "pgmer2.service_url",
+    "MeritRank service URL.",
+    "Comma-separated list of primary service URLs, tried in order. Defaults to MERITRANK_SERVICE_URL environment variable.",
+    &SERVICE_URL_GUC,
+    GucContext::Userset,
+    GucFlags::default(),
+  );
+
+  GucRegistry::define_string_guc(
+    "pgmer2.replica_urls",
+    "MeritRank read replica URLs.",
+    "Comma-separated list of service URLs for read-only functions. Defaults to MERITRANK_REPLICA_URLS environment variable.",
+    &REPLICA_URLS_GUC,
+    GucContext::Userset,
+    GucFlags::default(),
+  );
+
+  GucRegistry::define_int_guc(
+    "pgmer2.recv_timeout_ms",
+    "Timeout for receiving a response from the service.",
+    "-1 means MERITRANK_RECV_TIMEOUT_MSEC environment variable, or 10000.",
+    &RECV_TIMEOUT_GUC,
+    -1,
+    i32::MAX,
+    GucContext::Userset,
+    GucFlags::UNIT_MS,
+  );
+
+  GucRegistry::define_int_guc(
+    "pgmer2.send_timeout_ms",
+    "Timeout for sending a request to the service.",
+    "-1 means MERITRANK_SEND_TIMEOUT_MSEC environment variable, or no timeout.",
+    &SEND_TIMEOUT_GUC,
+    -1,
+    i32::MAX,
+    GucContext::Userset,
+    GucFlags::UNIT_MS,
+  );
+
+  GucRegistry::define_bool_guc(
+    "pgmer2.transactional",
+    "Buffer edge writes until COMMIT.",
+    "When on, mr_put_edge, mr_delete_edge and mr_delete_node are sent to the service only when the transaction commits, and discarded on rollback.",
+    &TRANSACTIONAL_GUC,
+    GucContext::Userset,
+    GucFlags::default(),
+  );
+
+  GucRegistry::define_bool_guc(
+    "pgmer2.outbox",
+    "Queue edge writes in the mr_outbox table.",
+    "When on, mr_put_edge, mr_delete_edge, mr_delete_node and other edge writes are stored in mr_outbox and sent to the service by the background worker.",
+    &OUTBOX_GUC,
+    GucContext::Userset,
+    GucFlags::default(),
+  );
+
+  GucRegistry::define_string_guc(
+    "pgmer2.outbox_database",
+    "Database of the outbox background worker.",
+    "The worker only runs when pgmer2 is in shared_preload_libraries. Defaults to postgres.",
+    &OUTBOX_DATABASE_GUC,
+    GucContext::Postmaster,
+    GucFlags::default(),
+  );
+
+  GucRegistry::define_int_guc(
+    "pgmer2.outbox_naptime_ms",
+    "Delay between outbox checks of the background worker.",
+    "Retries after a failure back off exponentially from this delay, up to one minute.",
+    &OUTBOX_NAPTIME_GUC,
+    1,
+    i32::MAX,
+    GucContext::Sighup,
+    GucFlags::UNIT_MS,
+  );
+
+  GucRegistry::define_int_guc(
+    "pgmer2.page_size",
+    "Number of rows fetched from the service per request by set-returning functions.",
+    "Rows are fetched page by page as the query reads them. 0 means the whole result in one request.",
+    &PAGE_SIZE_GUC,
+    0,
+    i32::MAX,
+    GucContext::Userset,
+    GucFlags::default(),
+  );
+
+  GucRegistry::define_bool_guc(
+    "pgmer2.cache",
+    "Cache service responses until the end of the transaction.",
+    "When on, repeated reads with the same arguments in one transaction return the same result. Writes clear the cache.",
+    &CACHE_GUC,
+    GucContext::Userset,
+    GucFlags::default(),
+  );
+
+  GucRegistry::define_int_guc(
+    "pgmer2.parallelism",
+    "Maximum number of requests in flight on one connection for batched calls.",
+    "Used by mr_scores_bulk, mr_node_scores, mr_export and other calls sending many requests.",
+    &PARALLELISM_GUC,
+    1,
+    1024,
+    GucContext::Userset,
+    GucFlags::default(),
+  );
+
+  GucRegistry::define_int_guc(
+    "pgmer2.shared_cache_size",
+    "Size of the read cache shared by all backends.",
+    "0 disables the shared cache. Requires pgmer2 in shared_preload_libraries.",
+    &SHARED_CACHE_SIZE_GUC,
+    0,
+    4 * 1024 * 1024,
+    GucContext::Postmaster,
+    GucFlags::UNIT_KB,
+  );
+
+  GucRegistry::define_int_guc(
+    "pgmer2.shared_cache_ttl_ms",
+    "Maximum age of shared cache entries.",
+    "Results may be stale for up to this long, since most writes are applied by the service after the request returns, and other clients may change the graph.",
+    &SHARED_CACHE_TTL_GUC,
+    1,
+    i32::MAX,
+    GucContext::Userset,
+    GucFlags::UNIT_MS,
+  );
+
+  if unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+    outbox::register_worker();
+    shared_cache::init();
+  }
+
+  unsafe {
+    pg_sys::RegisterXactCallback(Some(xact_callback), std::ptr::null_mut());
+    pg_sys::RegisterSubXactCallback(Some(subxact_callback), std::ptr::null_mut());
+  }
+}
+
 //  ================================================================
 //
 //    SQL
@@ -52,6 +253,18 @@ DROP FUNCTION IF EXISTS mr_mark_beacons;
 DROP FUNCTION IF EXISTS mr_unmarked_beacons;
 DROP VIEW     IF EXISTS mr_t_node;
 DROP VIEW     IF EXISTS mr_t_stats;
+DROP FUNCTION IF EXISTS mr_scores(text, boolean, text, text, double precision, double precision, double precision, double precision, integer, integer);
+
+-- Guarded, since the install script is also the update script.
+DO $$ BEGIN
+  CREATE TYPE mr_node_kind AS ENUM ('U', 'B', 'C');
+EXCEPTION WHEN duplicate_object THEN null;
+END $$;
+
+DO $$ BEGIN
+  CREATE TYPE mr_score_order AS ENUM ('score_desc', 'score_asc', 'name');
+EXCEPTION WHEN duplicate_object THEN null;
+END $$;
 
 CREATE OR REPLACE VIEW mr_t_edge AS SELECT
   '' ::text             AS src,
@@ -70,943 +283,4224 @@ CREATE OR REPLACE VIEW mr_t_mutual_score AS SELECT
   (0)::double precision AS dst_score,
   (0)::double precision AS src_score
   WHERE false;
+
+CREATE OR REPLACE VIEW mr_t_score AS SELECT
+  '' ::text             AS src,
+  '' ::text             AS dst,
+  (0)::double precision AS score,
+  (0)::bigint           AS rank,
+  NULL::mr_node_kind    AS dst_kind,
+  '' ::text             AS context
+  WHERE false;
 "#,
   name      = "bootstrap_raw",
   bootstrap,
-  creates   = [Type(mr_t_edge), Type(mr_t_link), Type(mr_t_mutual_score)],
+  creates   = [Type(mr_t_edge), Type(mr_t_link), Type(mr_t_mutual_score), Type(mr_t_score)],
 );
 
-//  ================================================================
-//
-//    Utils
-//
-//  ================================================================
+//  Enum types are created by the bootstrap SQL above. The PostgresEnum
+//  derive would emit a bare CREATE TYPE, which fails on update, so the
+//  conversions are implemented here.
+
+macro_rules! sql_enum {
+  ($name:ident { $($label:ident),* }) => {
+    impl FromDatum for $name {
+      unsafe fn from_polymorphic_datum(
+        datum   : pg_sys::Datum,
+        is_null : bool,
+        _       : pg_sys::Oid,
+      ) -> Option<$name> {
+        if is_null {
+          return None;
+        }
+
+        let (label, _, _) = pgrx::enum_helper::lookup_enum_by_oid(pg_sys::Oid::from_datum(datum, false)?);
+
+        match label.as_str() {
+          $(stringify!($label) => Some($name::$label),)*
+          _                    => ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_TEXT_REPRESENTATION,
+            format!("invalid input value for enum {}: \"{}\"", stringify!($name), label)
+          ),
+        }
+      }
+    }
 
-fn request_raw(payload : Vec<u8>, timeout_msec : Option<u64>) -> Result<Message, Box<dyn Error + 'static>> {
-  let client = Socket::new(Protocol::Req0)?;
-  match timeout_msec {
-    Some(t) => client.set_opt::<RecvTimeout>(Some(Duration::from_millis(t)))?,
-    _       => {}
-  }
-  client.dial(&SERVICE_URL)?;
-  client
-    .send(Message::from(payload.as_slice()))
-    .map_err(|(_, err)| err)?;
-  return Ok(client.recv()?);
-}
+    impl IntoDatum for $name {
+      fn into_datum(self) -> Option<pg_sys::Datum> {
+        let label = match self {
+          $($name::$label => stringify!($label),)*
+        };
 
-fn request<T>(
-  payload      : Vec<u8>,
-  timeout_msec : Option<u64>,
-) -> Result<T, Box<dyn Error + 'static>>
-  where T : Clone + for<'a> Deserialize<'a>
-{
-  let msg = request_raw(payload, timeout_msec)?;
-  let slice : &[u8] = msg.as_slice();
-  match decode_response(slice) {
-    Ok(x)  => Ok(x),
-    Err(s) => Err(s.into()),
-  }
-}
+        Some(pgrx::enum_helper::lookup_enum_by_label(stringify!($name), label))
+      }
 
-fn service_wrapped() -> Result<String, Box<dyn Error + 'static>> {
-  let payload  = rmp_serde::to_vec(&(
-    CMD_VERSION,
-    "",
-    true,
-    rmp_serde::to_vec(&())?
-  ))?;
+      fn type_oid() -> pg_sys::Oid {
+        pgrx::wrappers::regtypein(stringify!($name))
+      }
+    }
 
-  let response = request_raw(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  let s        = rmp_serde::from_slice(response.as_slice())?;
-  return Ok(s);
-}
+    unsafe impl SqlTranslatable for $name {
+      fn argument_sql() -> Result<SqlMapping, ArgumentError> {
+        Ok(SqlMapping::As(stringify!($name).to_string()))
+      }
 
-fn make_setof_edge(response : &Vec<(String, String, f64)>) -> Result<
-  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
-  Box<dyn Error + 'static>,
-> {
-  let tuples : Vec<PgHeapTuple<'_, AllocatedByRust>> =
-    response
-      .iter()
-      .map(|(ego, dst, score)| {
-        let mut edge = PgHeapTuple::new_composite_type("mr_t_edge").unwrap();
-        edge.set_by_name("src",    ego.as_str()).unwrap();
-        edge.set_by_name("dst",    dst.as_str()).unwrap();
-        edge.set_by_name("score",  *score)      .unwrap();
-        return edge;
-      })
-      .collect();
-  return Ok(SetOfIterator::new(tuples));
+      fn return_sql() -> Result<Returns, ReturnsError> {
+        Ok(Returns::One(SqlMapping::As(stringify!($name).to_string())))
+      }
+    }
+  };
 }
 
-fn make_setof_edge_for_src(
-  src      : &str,
-  response : &Vec<(String, f64)>
-) -> Result<
-  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
-  Box<dyn Error + 'static>,
-> {
-  let tuples : Vec<PgHeapTuple<'_, AllocatedByRust>> =
-    response
-      .iter()
-      .map(|(dst, score)| {
-        let mut edge = PgHeapTuple::new_composite_type("mr_t_edge").unwrap();
-        edge.set_by_name("src",    src)         .unwrap();
-        edge.set_by_name("dst",    dst.as_str()).unwrap();
-        edge.set_by_name("score",  *score)      .unwrap();
-        return edge;
-      })
-      .collect();
-  return Ok(SetOfIterator::new(tuples));
+//  Node kind, given by the first letter of the node name.
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum mr_node_kind {
+  //  User
+  U,
+  //  Beacon
+  B,
+  //  Comment
+  C,
 }
 
-fn make_setof_link(response : &Vec<(String, String)>) -> Result<
-  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_link")>,
-  Box<dyn Error + 'static>,
-> {
-  let tuples : Vec<PgHeapTuple<'_, AllocatedByRust>> =
-    response
-      .iter()
-      .map(|(ego, target)| {
-        let mut edge = PgHeapTuple::new_composite_type("mr_t_link").unwrap();
-        edge.set_by_name("src",    ego.as_str()).unwrap();
-        edge.set_by_name("dst", target.as_str()).unwrap();
-        return edge;
-      })
-      .collect();
-  return Ok(SetOfIterator::new(tuples));
+impl mr_node_kind {
+  fn prefix(self) -> &'static str {
+    match self {
+      mr_node_kind::U => "U",
+      mr_node_kind::B => "B",
+      mr_node_kind::C => "C",
+    }
+  }
+
+  fn of(name : &str) -> Option<mr_node_kind> {
+    match name.chars().next() {
+      Some('U') => Some(mr_node_kind::U),
+      Some('B') => Some(mr_node_kind::B),
+      Some('C') => Some(mr_node_kind::C),
+      _         => None,
+    }
+  }
+
+  //  Kind given as text, for functions that took a text kind before the
+  //  enum type existed. '' still means any kind.
+  fn parse(kind : Option<&str>) -> Result<Option<mr_node_kind>, MrError> {
+    match kind.unwrap_or("") {
+      ""  => Ok(None),
+      "U" => Ok(Some(mr_node_kind::U)),
+      "B" => Ok(Some(mr_node_kind::B)),
+      "C" => Ok(Some(mr_node_kind::C)),
+      x   => Err(MrError::InvalidArgument(format!("invalid input value for enum mr_node_kind: \"{}\"", x))),
+    }
+  }
 }
 
-fn make_setof_mutual_score(src : &str, response : &Vec<(String, f64, f64)>) -> Result<
-  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_mutual_score")>,
-  Box<dyn Error + 'static>,
-> {
-  let tuples : Vec<PgHeapTuple<'_, AllocatedByRust>> =
-    response
-      .iter()
-      .map(|(dst, dst_score, src_score)| {
-        let mut score = PgHeapTuple::new_composite_type("mr_t_mutual_score").unwrap();
-        score.set_by_name("src",       src).unwrap();
-        score.set_by_name("dst",       dst.as_str()).unwrap();
-        score.set_by_name("dst_score", *dst_score).unwrap();
-        score.set_by_name("src_score", *src_score).unwrap();
-        return score;
-      })
-      .collect();
-  return Ok(SetOfIterator::new(tuples));
+sql_enum!(mr_node_kind { U, B, C });
+
+//  Order of scores.
+
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum mr_score_order {
+  //  Highest score first
+  score_desc,
+  //  Lowest score first
+  score_asc,
+  //  By destination name
+  name,
 }
 
+sql_enum!(mr_score_order { score_desc, score_asc, name });
+
 //  ================================================================
 //
-//    Immutable functions
+//    Utils
 //
 //  ================================================================
 
-#[pg_extern(immutable)]
-fn mr_service_url() -> &'static str {
-  &SERVICE_URL
+fn service_url() -> String {
+  match SERVICE_URL_GUC.get() {
+    Some(s) if !s.to_bytes().is_empty() => s.to_string_lossy().into_owned(),
+    _                                    => ENV_SERVICE_URL.clone(),
+  }
 }
 
-#[pg_extern(immutable)]
-fn mr_connector() ->  &'static str {
-  VERSION
+fn recv_timeout_msec() -> u64 {
+  match RECV_TIMEOUT_GUC.get() {
+    x if x >= 0 => x as u64,
+    _           => *ENV_RECV_TIMEOUT_MSEC,
+  }
 }
 
-#[pg_extern(immutable)]
-fn mr_service() -> String {
-  match service_wrapped() {
-    Err(e) => format!("{}", e),
-    Ok(s)  => s
+fn send_timeout_msec() -> Option<u64> {
+  match SEND_TIMEOUT_GUC.get() {
+    x if x >= 0 => Some(x as u64),
+    _           => *ENV_SEND_TIMEOUT_MSEC,
   }
 }
 
-#[pg_extern(immutable)]
-fn mr_node_score(
-  src     : Option<&str>,
-  dst     : Option<&str>,
-  context : default!(Option<&str>, "''"),
-) -> Result<
-  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
-  Box<dyn Error + 'static>,
-> {
-  let context  = context.unwrap_or("");
-  let ego      = src.expect("src should not be null");
-  let target   = dst.expect("dst should not be null");
+fn split_urls(urls : &str) -> Vec<String> {
+  urls
+    .split(',')
+    .map(|x| x.trim())
+    .filter(|x| !x.is_empty())
+    .map(|x| x.to_string())
+    .collect()
+}
 
-  let args = rmp_serde::to_vec(&(
-    ego,
-    target
-  ))?;
+fn replica_urls() -> String {
+  match REPLICA_URLS_GUC.get() {
+    Some(s) => s.to_string_lossy().into_owned(),
+    None    => ENV_REPLICA_URLS.clone(),
+  }
+}
 
-  let payload  = encode_request(&Command {
-    id       : CMD_NODE_SCORE.to_string(),
-    context  : context.to_string(),
-    blocking : true,
-    payload  : args
-  })?;
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Route {
+  //  Mutating and session-state commands. Sent to the first reachable
+  //  URL from pgmer2.service_url.
+  Primary,
+  //  Read-only commands. Balanced across pgmer2.replica_urls, falling
+  //  back to the primary URLs.
+  Replica,
+}
 
-  let response = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return make_setof_edge(&response);
+thread_local! {
+  //  Start at a per-process offset, so backends don't all hit the same
+  //  replica first.
+  static NEXT_REPLICA : Cell<usize> = Cell::new(std::process::id() as usize);
 }
 
-fn scores_payload(
-  context       : Option<&str>,
-  src           : Option<&str>,
-  hide_personal : Option<bool>,
-  kind          : Option<&str>,
-  lt            : Option<f64>,
-  lte           : Option<f64>,
-  gt            : Option<f64>,
-  gte           : Option<f64>,
-  index         : Option<i32>,
-  count         : Option<i32>
-) -> Result<
-  Vec<u8>,
-  Box<dyn Error + 'static>,
-> {
-  let context       = context.unwrap_or("");
-  let ego           = src.expect("ego should not be null");
-  let hide_personal = hide_personal.unwrap_or(false);
-  let k             = kind.unwrap_or("");
-  let index         = index.unwrap_or(0) as u32;
-  let count         = count.unwrap_or(i32::MAX) as u32;
-  if lt.is_some() && lte.is_some() {
-    return Err(Box::from("either lt or lte is allowed!"));
+fn endpoints(route : Route) -> Vec<String> {
+  let primary = split_urls(&service_url());
+
+  if route == Route::Primary {
+    return primary;
   }
-  if gt.is_some() && gte.is_some() {
-    return Err(Box::from("either gt or gte is allowed!"));
+
+  let mut urls = split_urls(&replica_urls());
+
+  if !urls.is_empty() {
+    let n = NEXT_REPLICA.with(|x| {
+      let n = x.get();
+      x.set(n.wrapping_add(1));
+      n
+    });
+    urls.rotate_left(n % urls.len());
   }
 
-  let args = rmp_serde::to_vec(&(
-    ego,
-    k,
-    hide_personal,
-    lt.unwrap_or(lte.unwrap_or(i32::MAX.into())),
-    lte.is_some(),
-    gt.unwrap_or(gte.unwrap_or(i32::MIN.into())),
-    gte.is_some(),
-    index,
-    count
-  ))?;
+  for url in primary {
+    if !urls.contains(&url) {
+      urls.push(url);
+    }
+  }
 
-  let payload = encode_request(&Command {
-    id       : CMD_SCORES.to_string(),
-    context  : context.to_string(),
-    blocking : true,
-    payload  : args
-  });
+  return urls;
+}
+
+//  Backend-local connections to the service, reused across SQL calls.
+//  Postgres backends are single-threaded, so one socket per URL per
+//  process is enough. A socket is dropped and re-dialed whenever
+//  a request fails on it.
 
-  payload.map_err(|e| e.into())
+//  Sockets by URL. They are per process, so each parallel worker
+//  dials its own connections.
+
+thread_local! {
+  static CONNECTIONS : RefCell<HashMap<String, Socket>> = RefCell::new(HashMap::new());
 }
 
-#[pg_extern(immutable)]
-fn mr_scores(
-  src           : Option<&str>,
-  hide_personal : default!(Option<bool>, "false"),
-  context       : default!(Option<&str>, "''"),
-  kind          : default!(Option<&str>, "''"),
-  lt            : default!(Option<f64>,  "null"),
-  lte           : default!(Option<f64>,  "null"),
-  gt            : default!(Option<f64>,  "null"),
-  gte           : default!(Option<f64>,  "null"),
-  index         : default!(Option<i32>,  "0"),
-  count         : default!(Option<i32>,  "16")
-) -> Result<
-  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
-  Box<dyn Error + 'static>,
-> {
-  let payload = scores_payload(
-    context,
-    src,
-    hide_personal,
-    kind,
-    lt, lte,
-    gt, gte,
-    index,
-    count
-  )?;
+fn connect(url : &str) -> Result<Socket, nng::Error> {
+  CONNECTIONS.with(|sockets| {
+    let mut sockets = sockets.borrow_mut();
 
-  let response = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return make_setof_edge(&response);
+    match sockets.get(url) {
+      Some(socket) => return Ok(socket.clone()),
+      None         => {},
+    }
+
+    let socket = Socket::new(Protocol::Req0)?;
+    socket.dial(url)?;
+
+    sockets.insert(url.to_string(), socket.clone());
+    return Ok(socket);
+  })
 }
 
-#[pg_extern(immutable)]
-fn mr_graph(
-  src           : Option<&str>,
-  focus         : Option<&str>,
-  context       : default!(Option<&str>, "''"),
-  positive_only : default!(Option<bool>, "false"),
-  index         : default!(Option<i32>,  "0"),
-  count         : default!(Option<i32>,  "16")
-) -> Result<
-  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
-  Box<dyn Error + 'static>,
-> {
-  let context       = context.unwrap_or("");
-  let ego           = src.expect("src should not be null");
-  let focus         = focus.expect("focus should not be null");
-  let positive_only = positive_only.unwrap_or(false);
-  let index         = index.unwrap_or(0) as u32;
-  let count         = count.unwrap_or(i32::MAX) as u32;
+fn disconnect(url : &str) {
+  CONNECTIONS.with(|sockets| {
+    match sockets.borrow_mut().remove(url) {
+      Some(socket) => socket.close(),
+      None         => {},
+    }
+  });
+}
 
-  let args = rmp_serde::to_vec(&(
-    ego,
-    focus,
-    positive_only,
-    index,
-    count
-  ))?;
+fn disconnect_all() {
+  CONNECTIONS.with(|sockets| {
+    for (_, socket) in sockets.borrow_mut().drain() {
+      socket.close();
+    }
+  });
+}
 
-  let payload = encode_request(&Command {
-    id       : CMD_GRAPH.to_string(),
-    context  : context.to_string(),
-    blocking : true,
-    payload  : args
-  })?;
+//  Command id of an encoded request. Only needed for error reports,
+//  so it is decoded back from the payload instead of being passed around.
 
-  let response = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return make_setof_edge(&response);
+fn command_id(payload : &[u8]) -> String {
+  match rmp_serde::from_slice::<(String, IgnoredAny, IgnoredAny, IgnoredAny)>(payload) {
+    Ok((id, _, _, _)) => id,
+    Err(_)            => "unknown".to_string(),
+  }
 }
 
-#[pg_extern(immutable)]
-fn mr_nodelist(
-  context : default!(Option<&str>, "''")
-) -> Result<
-  SetOfIterator<'static, String>,
-  Box<dyn Error + 'static>,
-> {
-  let context = context.unwrap_or("");
+fn transport_error(
+  url          : &str,
+  payload      : &[u8],
+  timeout_msec : Option<u64>,
+  error        : nng::Error,
+) -> MrError {
+  match error {
+    nng::Error::TimedOut => MrError::Timeout {
+      url     : url.to_string(),
+      command : command_id(payload),
+      timeout_msec,
+    },
+    error => MrError::Connection {
+      url     : url.to_string(),
+      command : command_id(payload),
+      error,
+    },
+  }
+}
 
-  let payload = encode_request(&Command {
-    id       : CMD_NODE_LIST.to_string(),
-    context  : context.to_string(),
-    blocking : true,
-    payload  : rmp_serde::to_vec(&())?
-  })?;
+//  Waiting for the service is done in short slices, checking for query
+//  cancel and statement_timeout in between. On cancel the error unwinds
+//  through the caller, and dropping the Aio aborts the pending operation.
 
-  let response : Vec<_> = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
+const POLL_MSEC : u64 = 100;
 
-  let strings : Vec<String> =
-    response
-      .iter()
-      .map(|x : &(String,)| x.0.clone())
-      .collect();
-  return Ok(SetOfIterator::new(strings));
+fn wait_event<T>(rx : &Receiver<T>) -> Result<T, nng::Error> {
+  loop {
+    match rx.recv_timeout(Duration::from_millis(POLL_MSEC)) {
+      Ok(x)                               => return Ok(x),
+      Err(RecvTimeoutError::Timeout)      => check_for_interrupts!(),
+      Err(RecvTimeoutError::Disconnected) => return Err(nng::Error::Closed),
+    }
+  }
 }
 
-#[pg_extern(immutable)]
-fn mr_edgelist(
-  context : default!(Option<&str>, "''")
-) -> Result<
-  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
-  Box<dyn Error + 'static>,
-> {
-  let context = context.unwrap_or("");
+fn exchange(
+  client       : &Socket,
+  payload      : &[u8],
+  timeout_msec : Option<u64>,
+) -> Result<Message, nng::Error> {
+  let (tx, rx) = sync_channel::<nng::AioResult>(1);
 
-  let payload = encode_request(&Command {
-    id       : CMD_EDGES.to_string(),
-    context  : context.to_string(),
-    blocking : true,
-    payload  : rmp_serde::to_vec(&())?
+  let ctx = nng::Context::new(client)?;
+  let aio = nng::Aio::new(move |_, res| {
+    let _ = tx.send(res);
   })?;
 
-  let response = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return make_setof_edge(&response);
+  aio.set_timeout(send_timeout_msec().map(Duration::from_millis))?;
+  ctx.send(&aio, Message::from(payload)).map_err(|(_, err)| err)?;
+
+  match wait_event(&rx)? {
+    nng::AioResult::Send(Ok(_))         => {},
+    nng::AioResult::Send(Err((_, err))) => return Err(err),
+    _                                   => return Err(nng::Error::Internal),
+  }
+
+  aio.set_timeout(timeout_msec.map(Duration::from_millis))?;
+  ctx.recv(&aio)?;
+
+  match wait_event(&rx)? {
+    nng::AioResult::Recv(Ok(msg))  => Ok(msg),
+    nng::AioResult::Recv(Err(err)) => Err(err),
+    _                              => Err(nng::Error::Internal),
+  }
 }
 
-#[pg_extern(immutable)]
-fn mr_connected(
-  src     : Option<&str>,
-  context : default!(Option<&str>, "''")
-) -> Result<
-  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_link")>,
-  Box<dyn Error + 'static>,
-> {
-  let context = context.unwrap_or("");
-  let ego     = src.expect("src should not be null");
+//  With retry, a request that failed on a broken socket is sent again
+//  on a new one. Only pass it for requests that are safe to repeat:
+//  the service may have received and applied the first one.
 
-  let args = rmp_serde::to_vec(&(
-    ego
-  ))?;
+fn request_endpoint(
+  url          : &str,
+  payload      : &[u8],
+  timeout_msec : Option<u64>,
+  retry        : bool,
+) -> Result<Message, nng::Error> {
+  let client = connect(url)?;
+
+  match exchange(&client, payload, timeout_msec) {
+    Ok(msg) => Ok(msg),
+
+    //  Timeout means the service is busy, not that the socket is broken.
+    //  Still drop it, so no stale reply can be picked up later.
+    Err(nng::Error::TimedOut) => {
+      disconnect(url);
+      Err(nng::Error::TimedOut)
+    },
+
+    Err(e) if !retry => {
+      disconnect(url);
+      Err(e)
+    },
+
+    //  The socket went bad, e.g. the service was restarted.
+    //  Re-dial once and retry.
+    Err(_) => {
+      disconnect(url);
+      let client = connect(url)?;
+      exchange(&client, payload, timeout_msec).map_err(|e| {
+        disconnect(url);
+        e
+      })
+    },
+  }
+}
 
-  let payload = encode_request(&Command {
-    id       : CMD_CONNECTED.to_string(),
-    context  : context.to_string(),
-    blocking : true,
-    payload  : args
-  })?;
+//  Returns the response together with the URL that served it.
+//  Reads may be served from the cache. Writes invalidate it, see
+//  write_request.
+
+fn request_raw(
+  route        : Route,
+  payload      : Vec<u8>,
+  timeout_msec : Option<u64>,
+) -> Result<(String, Message), MrError> {
+  match route {
+    Route::Primary => request_uncached(route, &payload, timeout_msec),
 
-  let response = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return make_setof_link(&response);
+    Route::Replica if cache::enabled() => {
+      if let Some(x) = cache::get(&payload) {
+        return Ok(x);
+      }
+
+      let generation = cache::generation();
+      let (url, msg) = request_uncached(route, &payload, timeout_msec)?;
+      cache::put(&payload, &url, &msg, generation);
+      Ok((url, msg))
+    },
+
+    Route::Replica => request_uncached(route, &payload, timeout_msec),
+  }
 }
 
-#[pg_extern(immutable)]
-fn mr_mutual_scores(
-  src     : Option<&str>,
-  context : default!(Option<&str>, "''")
-) -> Result<
-  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_mutual_score")>,
-  Box<dyn Error + 'static>,
-> {
-  let ego     = src.expect("src should not be null");
-  let context = context.unwrap_or("");
+fn request_uncached(
+  route        : Route,
+  payload      : &[u8],
+  timeout_msec : Option<u64>,
+) -> Result<(String, Message), MrError> {
+  let mut last_error = MrError::NoEndpoint;
+
+  //  Reads are retried, and fail over to the next endpoint on dial and
+  //  timeout errors. Primary commands are not idempotent: once the
+  //  endpoint is dialed, a failed write may still be applied, so sending
+  //  it again, here or to another primary, could apply it twice. They
+  //  fail over on dial errors only, and are never resent.
+  for url in endpoints(route) {
+    if route == Route::Primary {
+      if let Err(e) = connect(&url) {
+        debug1!("pgmer2: dial {} failed: {}", url, e);
+        last_error = transport_error(&url, payload, timeout_msec, e);
+        continue;
+      }
 
-  let args = rmp_serde::to_vec(&(
-    ego
-  ))?;
+      return match request_endpoint(&url, payload, timeout_msec, false) {
+        Ok(msg) => Ok((url, msg)),
+        Err(e)  => Err(transport_error(&url, payload, timeout_msec, e)),
+      };
+    }
 
-  let payload = encode_request(&Command {
-    id       : CMD_MUTUAL_SCORES.to_string(),
-    context  : context.to_string(),
-    blocking : true,
-    payload  : args
-  })?;
+    match request_endpoint(&url, payload, timeout_msec, true) {
+      Ok(msg) => return Ok((url, msg)),
+      Err(e)  => {
+        debug1!("pgmer2: request to {} failed: {}", url, e);
+        last_error = transport_error(&url, payload, timeout_msec, e);
+      },
+    }
+  }
 
-  let response = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return make_setof_mutual_score(ego, &response);
+  return Err(last_error);
 }
 
-#[pg_extern]
-fn mr_get_new_edges_filter(
-  src : Option<&str>
-) -> Result<Vec<u8>, Box<dyn Error + 'static>> {
-  let src    = src.expect("src should not be null");
+//  Sends several requests over one socket at once, each on its own
+//  context, with at most pgmer2.parallelism of them in flight.
+//  Responses are returned in the order of payloads.
 
-  let args = rmp_serde::to_vec(&(
-    src
-  ))?;
+fn exchange_many(
+  client       : &Socket,
+  payloads     : &[Vec<u8>],
+  timeout_msec : Option<u64>,
+) -> Result<Vec<Message>, nng::Error> {
+  let send_timeout = send_timeout_msec().map(Duration::from_millis);
+  let recv_timeout = timeout_msec.map(Duration::from_millis);
 
-  let payload = encode_request(&Command {
-    id       : CMD_READ_NEW_EDGES_FILTER.to_string(),
-    context  : "".to_string(),
-    blocking : true,
-    payload  : args
-  })?;
+  let depth = PARALLELISM_GUC.get().max(1) as usize;
 
-  let response = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return Ok(response);
+  let (tx, rx) = sync_channel::<(usize, nng::AioResult)>(depth);
+
+  let mut slots = vec![];
+
+  for n in 0..depth.min(payloads.len()) {
+    let tx  = tx.clone();
+    let ctx = nng::Context::new(client)?;
+    let aio = nng::Aio::new(move |_, res| {
+      let _ = tx.send((n, res));
+    })?;
+    slots.push((ctx, aio, 0_usize));
+  }
+
+  let mut responses : Vec<Option<Message>> = payloads.iter().map(|_| None).collect();
+  let mut next      = 0;
+  let mut done      = 0;
+
+  for (ctx, aio, index) in slots.iter_mut() {
+    *index = next;
+    aio.set_timeout(send_timeout)?;
+    ctx.send(aio, Message::from(payloads[next].as_slice())).map_err(|(_, err)| err)?;
+    next += 1;
+  }
+
+  while done < payloads.len() {
+    let (n, res) = wait_event(&rx)?;
+    let (ctx, aio, index) = &mut slots[n];
+
+    match res {
+      nng::AioResult::Send(Ok(_)) => {
+        aio.set_timeout(recv_timeout)?;
+        ctx.recv(aio)?;
+      },
+
+      nng::AioResult::Recv(Ok(msg)) => {
+        responses[*index] = Some(msg);
+        done += 1;
+
+        if next < payloads.len() {
+          *index = next;
+          aio.set_timeout(send_timeout)?;
+          ctx.send(aio, Message::from(payloads[next].as_slice())).map_err(|(_, err)| err)?;
+          next += 1;
+        }
+      },
+
+      nng::AioResult::Send(Err((_, err))) => return Err(err),
+      nng::AioResult::Recv(Err(err))      => return Err(err),
+      nng::AioResult::Sleep(_)            => {},
+    }
+  }
+
+  return Ok(responses.into_iter().flatten().collect());
 }
 
-#[pg_extern(immutable)]
-fn mr_sync(
-  timeout_msec : default!(Option<i32>, "6000000"),
-) -> Result<
-  &'static str,
-  Box<dyn Error + 'static>
-> {
-  let timeout_msec = match timeout_msec { Some(x) => Some(x as u64), _ => None, };
+//  Like request_many_uncached, but only sends the requests missing
+//  from the response cache. The URL returned is the one that served
+//  the last response.
 
-  let payload = encode_request(&Command {
-    id       : CMD_SYNC.to_string(),
-    context  : "".to_string(),
-    blocking : true,
-    payload  : rmp_serde::to_vec(&())?
-  })?;
+fn request_many(
+  route        : Route,
+  payloads     : &[Vec<u8>],
+  timeout_msec : Option<u64>,
+) -> Result<(String, Vec<Message>), MrError> {
+  if route == Route::Primary || !cache::enabled() {
+    return request_many_uncached(route, payloads, timeout_msec);
+  }
 
-  let _ : () = request(payload, timeout_msec)?;
-  return Ok("Ok");
+  let mut responses : Vec<Option<(String, Message)>> =
+    payloads.iter().map(|x| cache::get(x)).collect();
+
+  let missing : Vec<usize> = (0..payloads.len()).filter(|&n| responses[n].is_none()).collect();
+
+  if !missing.is_empty() {
+    let generation = cache::generation();
+    let misses : Vec<Vec<u8>> = missing.iter().map(|&n| payloads[n].clone()).collect();
+    let (url, msgs) = request_many_uncached(route, &misses, timeout_msec)?;
+
+    for (n, msg) in missing.into_iter().zip(msgs.into_iter()) {
+      cache::put(&payloads[n], &url, &msg, generation);
+      responses[n] = Some((url.clone(), msg));
+    }
+  }
+
+  let url = match responses.last() {
+    Some(Some((url, _))) => url.clone(),
+    _                    => String::new(),
+  };
+
+  return Ok((url, responses.into_iter().flatten().map(|(_, msg)| msg).collect()));
 }
 
-//  ================================================================
-//
-//    Mutable functions
-//
-//  ================================================================
+fn request_many_uncached(
+  route        : Route,
+  payloads     : &[Vec<u8>],
+  timeout_msec : Option<u64>,
+) -> Result<(String, Vec<Message>), MrError> {
+  let mut last_error = MrError::NoEndpoint;
+
+  //  Only fail over when the endpoint can't be dialed. After that some of
+  //  the requests may already be processed, so resending them elsewhere
+  //  is up to the caller.
+  for url in endpoints(route) {
+    let client = match connect(&url) {
+      Ok(x)  => x,
+      Err(e) => {
+        debug1!("pgmer2: dial {} failed: {}", url, e);
+        last_error = transport_error(&url, &payloads[0], timeout_msec, e);
+        continue;
+      },
+    };
+
+    return match exchange_many(&client, payloads, timeout_msec) {
+      Ok(x)  => Ok((url, x)),
+      Err(e) => {
+        disconnect(&url);
+        Err(transport_error(&url, &payloads[0], timeout_msec, e))
+      },
+    };
+  }
 
-#[pg_extern]
-fn mr_log_level(
-  log_level : default!(Option<i32>, "1"),
-) -> Result<&'static str, Box<dyn Error + 'static>> {
-  let log_level = log_level.unwrap_or(0);
+  return Err(last_error);
+}
 
-  let payload = encode_request(&Command {
-    id       : CMD_LOG_LEVEL.to_string(),
-    context  : "".to_string(),
-    blocking : true,
-    payload  : rmp_serde::to_vec(&(log_level as u32))?
-  })?;
+fn decode<T>(
+  url     : &str,
+  payload : &[u8],
+  msg     : &Message,
+) -> Result<T, MrError>
+  where T : Clone + for<'a> Deserialize<'a>
+{
+  match decode_response(msg.as_slice()) {
+    Ok(x)  => Ok(x),
+    Err(s) => Err(MrError::Service {
+      url     : url.to_string(),
+      command : command_id(payload),
+      message : s.to_string(),
+    }),
+  }
+}
 
-  let _ : () = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return Ok("Ok");
+fn request<T>(
+  route        : Route,
+  payload      : Vec<u8>,
+  timeout_msec : Option<u64>,
+) -> Result<T, MrError>
+  where T : Clone + for<'a> Deserialize<'a>
+{
+  let (url, msg) = request_raw(route, payload.clone(), timeout_msec)?;
+  return decode(&url, &payload, &msg);
 }
 
-#[pg_extern]
-fn mr_create_context(
-  context : Option<&str>
-) -> Result<&'static str, Box<dyn Error + 'static>> {
-  let context = context.unwrap_or("");
+//  Sends a mutating command to the primary and invalidates the response
+//  cache. After the request, so a read racing with the write can't
+//  cache the result from before it.
+
+fn write_request<T>(
+  payload      : Vec<u8>,
+  timeout_msec : Option<u64>,
+) -> Result<T, MrError>
+  where T : Clone + for<'a> Deserialize<'a>
+{
+  let result = request(Route::Primary, payload, timeout_msec);
+  cache::invalidate();
+  return result;
+}
 
+fn command_payload<A : Serialize>(
+  id       : &str,
+  context  : &str,
+  blocking : bool,
+  args     : &A,
+) -> Result<Vec<u8>, MrError> {
   let payload = encode_request(&Command {
-    id       : CMD_CREATE_CONTEXT.to_string(),
+    id       : id.to_string(),
     context  : context.to_string(),
-    blocking : false,
-    payload  : rmp_serde::to_vec(&())?
-  })?;
+    blocking,
+    payload  : rmp_serde::to_vec(args)?
+  });
 
-  let _ : () = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return Ok("Ok");
+  payload.map_err(|e| MrError::Protocol(e.to_string()))
 }
 
-#[pg_extern]
-fn mr_put_edge(
-  src     : Option<&str>,
-  dst     : Option<&str>,
-  weight  : Option<f64>,
-  context : default!(Option<&str>, "''")
-) -> Result<
-  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
-  Box<dyn Error + 'static>,
-> {
-  let context = context.unwrap_or("");
-  let src     = src.expect("src should not be null");
-  let dest    = dst.expect("dst should not be null");
-  let weight  = weight.expect("weight should not be null");
+fn required<T>(x : Option<T>, name : &str) -> Result<T, MrError> {
+  match x {
+    Some(x) => Ok(x),
+    None    => Err(MrError::NullArgument(name.to_string())),
+  }
+}
 
-  let args = rmp_serde::to_vec(&(
-    src,
-    dest,
-    weight
+fn service_wrapped() -> Result<String, MrError> {
+  let payload  = rmp_serde::to_vec(&(
+    CMD_VERSION,
+    "",
+    true,
+    rmp_serde::to_vec(&())?
   ))?;
 
-  let payload = encode_request(&Command {
-    id       : CMD_PUT_EDGE.to_string(),
-    context  : context.to_string(),
-    blocking : false,
-    payload  : args
-  })?;
+  let (_, response) = request_raw(Route::Primary, payload, Some(recv_timeout_msec()))?;
+  let s             = rmp_serde::from_slice(response.as_slice())?;
+  return Ok(s);
+}
+
+//  Writes buffered in transactional mode, tagged with the subtransaction
+//  that issued them.
 
-  let _ : () = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return make_setof_edge(&vec![(src.to_string(), dest.to_string(), weight)]);
+thread_local! {
+  static PENDING_WRITES : RefCell<Vec<(pg_sys::SubTransactionId, Vec<u8>)>> = RefCell::new(vec![]);
 }
 
-#[pg_extern]
-fn mr_delete_edge(
-  src     : Option<&str>,
-  dst     : Option<&str>,
-  context : default!(Option<&str>, "''")
-) -> Result<&'static str, Box<dyn Error + 'static>> {
-  let context = context.unwrap_or("");
-  let ego     = src.expect("src should not be null");
-  let target  = dst.expect("dst should not be null");
+fn write_command(payload : Vec<u8>) -> Result<(), MrError> {
+  cache::clear();
 
-  let args = rmp_serde::to_vec(&(
-    ego,
-    target
-  ))?;
+  if OUTBOX_GUC.get() {
+    let _ = outbox::enqueue(vec![payload])?;
+    return Ok(());
+  }
 
-  let payload = encode_request(&Command {
-    id       : CMD_DELETE_EDGE.to_string(),
-    context  : context.to_string(),
-    blocking : false,
-    payload  : args
-  })?;
+  if TRANSACTIONAL_GUC.get() {
+    let subid = unsafe { pg_sys::GetCurrentSubTransactionId() };
+    PENDING_WRITES.with(|q| q.borrow_mut().push((subid, payload)));
+    return Ok(());
+  }
 
-  let _ : () = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return Ok("Ok");
+  let _ : () = write_request(payload, Some(recv_timeout_msec()))?;
+  return Ok(());
 }
 
-#[pg_extern]
-fn mr_delete_node(
-  src     : Option<&str>,
-  context : default!(Option<&str>, "''")
-) -> Result<&'static str, Box<dyn Error + 'static>> {
-  let context = context.unwrap_or("");
-  let ego     = src.expect("src should not be null");
+//  Sends a batch of mutating commands, returns the number of commands
+//  the service accepted.
 
-  let args = rmp_serde::to_vec(&(
-    ego
-  ))?;
+fn write_commands(payloads : Vec<Vec<u8>>) -> Result<usize, MrError> {
+  cache::clear();
 
-  let payload = encode_request(&Command {
-    id       : CMD_DELETE_NODE.to_string(),
-    context  : context.to_string(),
-    blocking : false,
-    payload  : args
-  })?;
+  if OUTBOX_GUC.get() {
+    return outbox::enqueue(payloads);
+  }
 
-  let _ : () = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return Ok("Ok");
+  if TRANSACTIONAL_GUC.get() {
+    return Ok(buffer_writes(payloads));
+  }
+
+  if payloads.is_empty() {
+    return Ok(0);
+  }
+
+  let result = request_many(Route::Primary, &payloads, Some(recv_timeout_msec()));
+  cache::invalidate();
+  let (url, responses) = result?;
+
+  let mut applied = 0;
+  let mut errors  = vec![];
+
+  for (payload, msg) in payloads.iter().zip(responses.iter()) {
+    match decode::<()>(&url, payload, msg) {
+      Ok(()) => applied += 1,
+      Err(e) => errors.push(e),
+    }
+  }
+
+  match errors.into_iter().next() {
+    Some(MrError::Service { url, command, message }) => Err(MrError::Service {
+      url,
+      command,
+      message : format!("{} of {} commands applied: {}", applied, payloads.len(), message),
+    }),
+    Some(e) => Err(e),
+    None    => Ok(applied),
+  }
 }
 
-#[pg_extern]
-fn mr_set_new_edges_filter(
-  src    : Option<&str>,
-  filter : Option<Vec<u8>>,
-) -> Result<&'static str, Box<dyn Error + 'static>> {
-  let src    = src.expect("src should not be null");
-  let filter = filter.expect("filter should not be null");
+fn buffer_writes(payloads : Vec<Vec<u8>>) -> usize {
+  let n     = payloads.len();
+  let subid = unsafe { pg_sys::GetCurrentSubTransactionId() };
+  PENDING_WRITES.with(|q| q.borrow_mut().extend(payloads.into_iter().map(|x| (subid, x))));
+  return n;
+}
 
-  let args = rmp_serde::to_vec(&(
-    src,
-    filter
-  ))?;
+//  Like write_commands, but the commands take effect only if the
+//  transaction commits, whatever pgmer2.transactional is: they go to
+//  the outbox if it is enabled, and are buffered until commit otherwise.
 
-  let payload = encode_request(&Command {
-    id       : CMD_WRITE_NEW_EDGES_FILTER.to_string(),
-    context  : "".to_string(),
-    blocking : false,
-    payload  : args
-  })?;
+fn write_commands_on_commit(payloads : Vec<Vec<u8>>) -> Result<usize, MrError> {
+  cache::clear();
 
-  let _ = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return Ok("Ok");
+  if OUTBOX_GUC.get() {
+    return outbox::enqueue(payloads);
+  }
+
+  return Ok(buffer_writes(payloads));
 }
 
-#[pg_extern]
-fn mr_fetch_new_edges(
-  src    : Option<&str>,
-  prefix : default!(Option<&str>, "''"),
-) -> Result<
-  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
-  Box<dyn Error + 'static>,
-> {
-  let src    = src.expect("src should not be null");
-  let prefix = prefix.unwrap_or("");
+fn put_edge_payload(
+  context : &str,
+  src     : &str,
+  dst     : &str,
+  weight  : f64,
+) -> Result<Vec<u8>, MrError> {
+  command_payload(CMD_PUT_EDGE, context, false, &(src, dst, weight))
+}
 
-  let args = rmp_serde::to_vec(&(
-    src,
-    prefix
-  ))?;
+fn delete_edge_payload(
+  context : &str,
+  src     : &str,
+  dst     : &str,
+) -> Result<Vec<u8>, MrError> {
+  command_payload(CMD_DELETE_EDGE, context, false, &(src, dst))
+}
 
-  let payload = encode_request(&Command {
-    id       : CMD_FETCH_NEW_EDGES.to_string(),
-    context  : "".to_string(),
-    blocking : true,
-    payload  : args
-  })?;
+fn delete_node_payload(
+  context : &str,
+  node    : &str,
+) -> Result<Vec<u8>, MrError> {
+  command_payload(CMD_DELETE_NODE, context, false, &(node))
+}
 
-  let response = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return make_setof_edge_for_src(src, &response);
+fn fetch_edges(
+  route   : Route,
+  context : &str,
+) -> Result<Vec<(String, String, f64)>, MrError> {
+  let payload = command_payload(CMD_EDGES, context, true, &())?;
+  request(route, payload, Some(recv_timeout_msec()))
 }
 
-#[pg_extern]
-fn mr_reset() -> Result<
-  &'static str,
-  Box<dyn Error + 'static>,
-> {
-  let payload  = encode_request(&Command {
-    id       : CMD_RESET.to_string(),
-    context  : "".to_string(),
-    blocking : false,
-    payload  : rmp_serde::to_vec(&())?
-  })?;
+//  Rows fetched from the service lazily, one page at a time, as the
+//  executor pulls them. Only the current page is held in memory.
+//  The first page is fetched on creation, so its errors are returned
+//  normally. Errors on later pages are raised while reading.
+
+struct Pages<T, F> {
+  fetch     : F,
+  index     : u32,
+  remaining : u32,
+  page_size : u32,
+  done      : bool,
+  page      : std::vec::IntoIter<T>,
+}
 
-  let _ : () = request(payload, Some(*RECV_TIMEOUT_MSEC))?;
-  return Ok("Ok");
+impl<T, F> Pages<T, F>
+where
+  F : FnMut(u32, u32) -> Result<Vec<T>, MrError>,
+{
+  fn new(index : u32, count : u32, fetch : F) -> Result<Self, MrError> {
+    let page_size = match PAGE_SIZE_GUC.get() {
+      0 => count,
+      x => (x as u32).min(count),
+    };
+
+    let mut pages = Pages {
+      fetch,
+      index,
+      remaining : count,
+      page_size,
+      done      : count == 0,
+      page      : vec![].into_iter(),
+    };
+
+    pages.next_page()?;
+    return Ok(pages);
+  }
+
+  fn next_page(&mut self) -> Result<(), MrError> {
+    if self.done {
+      return Ok(());
+    }
+
+    let n    = self.page_size.min(self.remaining);
+    let rows = (self.fetch)(self.index, n)?;
+    let got  = rows.len() as u32;
+
+    //  A short page is the last one.
+    self.done       = got < n || got == self.remaining;
+    self.index     += got;
+    self.remaining -= got.min(self.remaining);
+    self.page       = rows.into_iter();
+
+    return Ok(());
+  }
 }
 
-#[pg_extern]
-fn mr_zerorec(
-  blocking     : default!(Option<bool>, "true"),
-  timeout_msec : default!(Option<i32>,  "6000000")
-) -> Result<
-  &'static str,
-  Box<dyn Error + 'static>,
-> {
-  let blocking     = blocking.unwrap_or(true);
-  let timeout_msec = match timeout_msec { Some(x) => Some(x as u64), _ => None, };
+impl<T, F> Iterator for Pages<T, F>
+where
+  F : FnMut(u32, u32) -> Result<Vec<T>, MrError>,
+{
+  type Item = T;
 
-  let payload  = encode_request(&Command {
-    id       : CMD_RECALCULATE_ZERO.to_string(),
-    context  : "".to_string(),
-    blocking,
-    payload  : rmp_serde::to_vec(&())?
-  })?;
+  fn next(&mut self) -> Option<T> {
+    loop {
+      if let Some(row) = self.page.next() {
+        return Some(row);
+      }
+      if self.done {
+        return None;
+      }
+      if let Err(e) = self.next_page() {
+        e.report();
+      }
+    }
+  }
+}
 
-  let _ : () = request(payload, timeout_msec)?;
-  return Ok("Ok");
+//  Edges of a context as (src, dst, weight). The service returns all
+//  edges at once, so they are all held in memory. Only the rows built
+//  from them are made page by page.
+
+fn edge_pages(
+  route   : Route,
+  context : &str,
+) -> Result<impl Iterator<Item = (String, String, f64)>, MrError> {
+  let mut edges = fetch_edges(route, context)?.into_iter();
+  let count     = edges.len().min(u32::MAX as usize) as u32;
+
+  Pages::new(0, count, move |_, count| {
+    Ok(edges.by_ref().take(count as usize).collect())
+  })
 }
 
-//  ================================================================
-//
-//    Tests
-//
-//  ================================================================
+fn pending_writes() -> usize {
+  PENDING_WRITES.with(|q| q.borrow().len())
+}
 
-#[cfg(any(test, feature = "pg_test"))]
-#[pg_schema]
-mod tests {
-  use pgrx::prelude::*;
-  use super::testing::*;
-  use std::time::SystemTime;
+fn flush_pending_writes() -> Result<(), MrError> {
+  let pending = PENDING_WRITES.with(|q| q.take());
 
-  fn unpack_edge(x : &PgHeapTuple<'static, pgrx::AllocatedByRust>) -> (String, String, f64) {
-    return (
-      x.get_by_name("src").unwrap().unwrap(),
-      x.get_by_name("dst").unwrap().unwrap(),
-      x.get_by_name("score").unwrap().unwrap()
-    );
+  for (_, payload) in pending {
+    let _ : () = write_request(payload, Some(recv_timeout_msec()))?;
   }
 
-  fn collect_edges(i : SetOfIterator<'_, PgHeapTuple<'static, pgrx::AllocatedByRust>>) -> Vec<(String, String, f64)> {
-    i.map(|x| unpack_edge(&x)).collect()
+  return Ok(());
+}
+
+#[pg_guard]
+unsafe extern "C" fn xact_callback(
+  event : pg_sys::XactEvent,
+  _arg  : *mut std::ffi::c_void,
+) {
+  match event {
+    //  Flush before the commit becomes final, so a failure still aborts
+    //  the transaction.
+    pg_sys::XactEvent_XACT_EVENT_PRE_COMMIT => {
+      match outbox::pre_commit().and_then(|_| flush_pending_writes()) {
+        Ok(_)  => {},
+        Err(e) => e.report(),
+      }
+    },
+
+    pg_sys::XactEvent_XACT_EVENT_PRE_PREPARE => {
+      if pending_writes() > 0 || outbox::enqueued() {
+        error!("pgmer2: cannot PREPARE a transaction with pending graph writes");
+      }
+    },
+
+    pg_sys::XactEvent_XACT_EVENT_COMMIT          |
+    pg_sys::XactEvent_XACT_EVENT_PARALLEL_COMMIT |
+    pg_sys::XactEvent_XACT_EVENT_PREPARE => {
+      cache::clear();
+    },
+
+    pg_sys::XactEvent_XACT_EVENT_ABORT |
+    pg_sys::XactEvent_XACT_EVENT_PARALLEL_ABORT => {
+      PENDING_WRITES.with(|q| q.borrow_mut().clear());
+      outbox::reset();
+      cache::clear();
+    },
+
+    _ => {},
+  }
+}
+
+#[pg_guard]
+unsafe extern "C" fn subxact_callback(
+  event        : pg_sys::SubXactEvent,
+  my_subid     : pg_sys::SubTransactionId,
+  parent_subid : pg_sys::SubTransactionId,
+  _arg         : *mut std::ffi::c_void,
+) {
+  match event {
+    pg_sys::SubXactEvent_SUBXACT_EVENT_COMMIT_SUB => {
+      PENDING_WRITES.with(|q| {
+        for x in q.borrow_mut().iter_mut() {
+          if x.0 == my_subid {
+            x.0 = parent_subid;
+          }
+        }
+      });
+    },
+
+    pg_sys::SubXactEvent_SUBXACT_EVENT_ABORT_SUB => {
+      PENDING_WRITES.with(|q| q.borrow_mut().retain(|x| x.0 != my_subid));
+    },
+
+    _ => {},
+  }
+}
+
+//  Builds a set of composite values of the given type, filling each
+//  tuple from a row. Rows are converted as the executor pulls them,
+//  so a lazy iterator such as Pages is never collected in full.
+
+fn make_setof<T : 'static>(
+  type_name : &'static str,
+  rows      : impl IntoIterator<Item = T> + 'static,
+  fill      : impl Fn(&mut PgHeapTuple<'static, AllocatedByRust>, T) + 'static,
+) -> Result<
+  SetOfIterator<'static, PgHeapTuple<'static, AllocatedByRust>>,
+  MrError,
+> {
+  //  Fail early if the type is missing, instead of on the first row.
+  PgHeapTuple::new_composite_type(type_name)
+    .map_err(|e| MrError::Internal(e.to_string()))?;
+
+  let tuples = rows.into_iter().map(move |row| {
+    let mut tuple = PgHeapTuple::new_composite_type(type_name).unwrap();
+    fill(&mut tuple, row);
+    tuple
+  });
+
+  return Ok(SetOfIterator::new(tuples));
+}
+
+fn make_setof_edge(
+  response : impl IntoIterator<Item = (String, String, f64)> + 'static
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
+  MrError,
+> {
+  make_setof("mr_t_edge", response, |edge, (ego, dst, score)| {
+    edge.set_by_name("src",    ego)  .unwrap();
+    edge.set_by_name("dst",    dst)  .unwrap();
+    edge.set_by_name("score",  score).unwrap();
+  })
+}
+
+fn make_setof_edge_for_src(
+  src      : &str,
+  response : Vec<(String, f64)>
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
+  MrError,
+> {
+  let src = src.to_string();
+
+  make_setof("mr_t_edge", response, move |edge, (dst, score)| {
+    edge.set_by_name("src",    src.as_str()).unwrap();
+    edge.set_by_name("dst",    dst)         .unwrap();
+    edge.set_by_name("score",  score)       .unwrap();
+  })
+}
+
+fn make_setof_link(response : Vec<(String, String)>) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_link")>,
+  MrError,
+> {
+  make_setof("mr_t_link", response, |edge, (ego, target)| {
+    edge.set_by_name("src",    ego).unwrap();
+    edge.set_by_name("dst", target).unwrap();
+  })
+}
+
+fn make_setof_mutual_score(src : &str, response : Vec<(String, f64, f64)>) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_mutual_score")>,
+  MrError,
+> {
+  let src = src.to_string();
+
+  make_setof("mr_t_mutual_score", response, move |score, (dst, dst_score, src_score)| {
+    score.set_by_name("src",       src.as_str()).unwrap();
+    score.set_by_name("dst",       dst).unwrap();
+    score.set_by_name("dst_score", dst_score).unwrap();
+    score.set_by_name("src_score", src_score).unwrap();
+  })
+}
+
+//  Scores with their rank, counted from 1 at the first score of
+//  the whole result, so ranks continue across pages.
+
+fn make_setof_score(
+  context    : &str,
+  first_rank : i64,
+  response   : impl IntoIterator<Item = (String, String, f64)> + 'static,
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_score")>,
+  MrError,
+> {
+  let context = context.to_string();
+  let rows    = response.into_iter().zip(first_rank..);
+
+  make_setof("mr_t_score", rows, move |score, ((ego, dst, value), rank)| {
+    let kind = mr_node_kind::of(&dst);
+    score.set_by_name("src",      ego)            .unwrap();
+    score.set_by_name("dst",      dst)            .unwrap();
+    score.set_by_name("score",    value)          .unwrap();
+    score.set_by_name("rank",     rank)           .unwrap();
+    score.set_by_name("dst_kind", kind)           .unwrap();
+    score.set_by_name("context",  context.as_str()).unwrap();
+  })
+}
+
+//  ================================================================
+//
+//    Immutable functions
+//
+//  ================================================================
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_service_url() -> String {
+  service_url()
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_service_endpoints() -> TableIterator<
+  'static,
+  (name!(url, String), name!(role, String)),
+> {
+  let primary  = split_urls(&service_url());
+  let replicas = split_urls(&replica_urls());
+
+  let rows : Vec<(String, String)> =
+    primary
+      .into_iter()
+      .map(|url| (url, "primary".to_string()))
+      .chain(replicas.into_iter().map(|url| (url, "replica".to_string())))
+      .collect();
+
+  return TableIterator::new(rows);
+}
+
+#[pg_extern(immutable, parallel_safe)]
+fn mr_connector() ->  &'static str {
+  VERSION
+}
+
+//  Kind of a node by its name, or null for names without a known prefix.
+
+#[pg_extern(immutable, parallel_safe, name = "mr_node_kind")]
+fn node_kind(
+  name : Option<&str>,
+) -> Option<mr_node_kind> {
+  mr_node_kind::of(name?)
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_service() -> String {
+  match service_wrapped() {
+    Err(e) => format!("{}", e),
+    Ok(s)  => s
+  }
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_node_score(
+  src     : Option<&str>,
+  dst     : Option<&str>,
+  context : default!(Option<&str>, "''"),
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
+  ErrorReport,
+> {
+  let context  = context.unwrap_or("");
+  let ego      = required(src, "src")?;
+  let target   = required(dst, "dst")?;
+
+  let payload  = command_payload(CMD_NODE_SCORE, context, true, &(ego, target))?;
+  let response = request(Route::Replica, payload, Some(recv_timeout_msec()))?;
+  return Ok(make_setof_edge(response)?);
+}
+
+//  Scores of (src, dst) pairs, pipelined on one connection.
+
+fn node_scores(
+  context : &str,
+  pairs   : Vec<(&str, &str)>,
+) -> Result<Vec<(String, String, f64)>, MrError> {
+  if pairs.is_empty() {
+    return Ok(vec![]);
+  }
+
+  let mut payloads = vec![];
+
+  for (ego, target) in pairs {
+    payloads.push(command_payload(CMD_NODE_SCORE, context, true, &(ego, target))?);
+  }
+
+  let (url, responses) = request_many(Route::Replica, &payloads, Some(recv_timeout_msec()))?;
+
+  let mut scores = vec![];
+
+  for (payload, msg) in payloads.iter().zip(responses.iter()) {
+    let response : Vec<(String, String, f64)> = decode(&url, payload, msg)?;
+    scores.extend(response);
+  }
+
+  return Ok(scores);
+}
+
+#[pg_extern(stable, parallel_safe, name = "mr_node_scores")]
+fn mr_node_scores_for_src(
+  src     : Option<&str>,
+  dsts    : Option<Vec<String>>,
+  context : default!(Option<&str>, "''"),
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
+  ErrorReport,
+> {
+  let context = context.unwrap_or("");
+  let ego     = required(src,  "src")?;
+  let dsts    = required(dsts, "dsts")?;
+
+  let pairs    = dsts.iter().map(|dst| (ego, dst.as_str())).collect();
+  let response = node_scores(context, pairs)?;
+  return Ok(make_setof_edge(response)?);
+}
+
+#[pg_extern(stable, parallel_safe, name = "mr_node_scores")]
+fn mr_node_scores_for_srcs(
+  srcs    : Option<Vec<String>>,
+  dsts    : Option<Vec<String>>,
+  context : default!(Option<&str>, "''"),
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
+  ErrorReport,
+> {
+  let context = context.unwrap_or("");
+  let srcs    = required(srcs, "srcs")?;
+  let dsts    = required(dsts, "dsts")?;
+
+  let pairs =
+    srcs
+      .iter()
+      .flat_map(|src| dsts.iter().map(move |dst| (src.as_str(), dst.as_str())))
+      .collect();
+
+  let response = node_scores(context, pairs)?;
+  return Ok(make_setof_edge(response)?);
+}
+
+fn scores_payload(
+  context       : Option<&str>,
+  src           : Option<&str>,
+  hide_personal : Option<bool>,
+  kind          : Option<mr_node_kind>,
+  lt            : Option<f64>,
+  lte           : Option<f64>,
+  gt            : Option<f64>,
+  gte           : Option<f64>,
+  index         : Option<i32>,
+  count         : Option<i32>,
+  order_by      : Option<mr_score_order>,
+  hide_negative : Option<bool>,
+  min_abs_score : Option<f64>
+) -> Result<
+  Vec<u8>,
+  MrError,
+> {
+  let context       = context.unwrap_or("");
+  let ego           = required(src, "src")?;
+  let hide_personal = hide_personal.unwrap_or(false);
+  let k             = kind.map(|x| x.prefix()).unwrap_or("");
+  let index         = index.unwrap_or(0) as u32;
+  let count         = count.unwrap_or(i32::MAX) as u32;
+  if lt.is_some() && lte.is_some() {
+    return Err(MrError::InvalidArgument("either lt or lte is allowed!".to_string()));
+  }
+  if gt.is_some() && gte.is_some() {
+    return Err(MrError::InvalidArgument("either gt or gte is allowed!".to_string()));
+  }
+  if min_abs_score.map_or(false, |x| x.is_nan() || x < 0.0) {
+    return Err(MrError::InvalidArgument("min_abs_score should not be negative".to_string()));
+  }
+
+  //  Hiding negative scores is a lower bound of 0, unless the given
+  //  bound is already higher.
+  let (gt, gte) = match (hide_negative.unwrap_or(false), gt, gte) {
+    (true, Some(x), _) if x >= 0.0 => (Some(x), None),
+    (true, _, Some(x)) if x >= 0.0 => (None, Some(x)),
+    (true, _, _)                   => (None, Some(0.0)),
+    (false, gt, gte)               => (gt, gte),
+  };
+
+  let args = (
+    ego,
+    k,
+    hide_personal,
+    lt.unwrap_or(lte.unwrap_or(i32::MAX.into())),
+    lte.is_some(),
+    gt.unwrap_or(gte.unwrap_or(i32::MIN.into())),
+    gte.is_some(),
+    index,
+    count
+  );
+
+  //  Reordered scores are all fetched, and index and count applied
+  //  after sorting, see reorder_scores.
+  let args = match reordered(order_by, min_abs_score) {
+    true  => (args.0, args.1, args.2, args.3, args.4, args.5, args.6, 0, u32::MAX),
+    false => args,
+  };
+
+  command_payload(CMD_SCORES, context, true, &args)
+}
+
+//  The scores command returns the highest scores first and has no
+//  filter by absolute value, so other orders and min_abs_score are
+//  applied here, over all scores within the bounds.
+
+fn reordered(
+  order_by      : Option<mr_score_order>,
+  min_abs_score : Option<f64>,
+) -> bool {
+  order_by.unwrap_or(mr_score_order::score_desc) != mr_score_order::score_desc
+    || min_abs_score.is_some()
+}
+
+fn reorder_scores(
+  scores        : Vec<(String, String, f64)>,
+  index         : Option<i32>,
+  count         : Option<i32>,
+  order_by      : Option<mr_score_order>,
+  min_abs_score : Option<f64>
+) -> Vec<(String, String, f64)> {
+  let min_abs_score = min_abs_score.unwrap_or(0.0);
+
+  let mut scores : Vec<(String, String, f64)> =
+    scores
+      .into_iter()
+      .filter(|x| x.2.abs() >= min_abs_score)
+      .collect();
+
+  match order_by.unwrap_or(mr_score_order::score_desc) {
+    mr_score_order::score_desc => scores.sort_by(|a, b| b.2.total_cmp(&a.2)),
+    mr_score_order::score_asc  => scores.sort_by(|a, b| a.2.total_cmp(&b.2)),
+    mr_score_order::name       => scores.sort_by(|a, b| a.1.cmp(&b.1)),
+  }
+
+  scores
+    .into_iter()
+    .skip(index.unwrap_or(0).max(0) as usize)
+    .take(count.unwrap_or(i32::MAX).max(0) as usize)
+    .collect()
+}
+
+//  Scores fetched in pages of pgmer2.page_size, within index and count.
+//  Reordered scores are fetched at once.
+
+fn score_pages(
+  context       : Option<&str>,
+  src           : Option<&str>,
+  hide_personal : Option<bool>,
+  kind          : Option<mr_node_kind>,
+  lt            : Option<f64>,
+  lte           : Option<f64>,
+  gt            : Option<f64>,
+  gte           : Option<f64>,
+  index         : Option<i32>,
+  count         : Option<i32>,
+  order_by      : Option<mr_score_order>,
+  hide_negative : Option<bool>,
+  min_abs_score : Option<f64>
+) -> Result<
+  Box<dyn Iterator<Item = (String, String, f64)>>,
+  MrError,
+> {
+  if reordered(order_by, min_abs_score) {
+    let payload = scores_payload(
+      context,
+      src,
+      hide_personal,
+      kind,
+      lt, lte,
+      gt, gte,
+      index,
+      count,
+      order_by,
+      hide_negative,
+      min_abs_score
+    )?;
+    let response = request(Route::Replica, payload, Some(recv_timeout_msec()))?;
+    let scores   = reorder_scores(response, index, count, order_by, min_abs_score);
+    return Ok(Box::new(scores.into_iter()));
+  }
+
+  let context = context.map(|x| x.to_string());
+  let src     = src.map(|x| x.to_string());
+  let index   = index.unwrap_or(0) as u32;
+  let count   = count.unwrap_or(i32::MAX) as u32;
+
+  let pages = Pages::new(index, count, move |index, count| {
+    let payload = scores_payload(
+      context.as_deref(),
+      src.as_deref(),
+      hide_personal,
+      kind,
+      lt, lte,
+      gt, gte,
+      Some(index as i32),
+      Some(count as i32),
+      order_by,
+      hide_negative,
+      min_abs_score
+    )?;
+    request(Route::Replica, payload, Some(recv_timeout_msec()))
+  })?;
+
+  return Ok(Box::new(pages));
+}
+
+//  kind stays text, as in earlier versions, so existing callers passing
+//  kind => '' keep working. An enum overload next to it would make
+//  calls that leave kind out ambiguous.
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_scores(
+  src           : Option<&str>,
+  hide_personal : default!(Option<bool>, "false"),
+  context       : default!(Option<&str>, "''"),
+  kind          : default!(Option<&str>, "''"),
+  lt            : default!(Option<f64>,  "null"),
+  lte           : default!(Option<f64>,  "null"),
+  gt            : default!(Option<f64>,  "null"),
+  gte           : default!(Option<f64>,  "null"),
+  index         : default!(Option<i32>,  "0"),
+  count         : default!(Option<i32>,  "16"),
+  order_by      : default!(Option<mr_score_order>, "'score_desc'"),
+  hide_negative : default!(Option<bool>, "false"),
+  min_abs_score : default!(Option<f64>,  "null")
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
+  ErrorReport,
+> {
+  let response = score_pages(
+    context,
+    src,
+    hide_personal,
+    mr_node_kind::parse(kind)?,
+    lt, lte,
+    gt, gte,
+    index,
+    count,
+    order_by,
+    hide_negative,
+    min_abs_score
+  )?;
+  return Ok(make_setof_edge(response)?);
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_ranked_scores(
+  src           : Option<&str>,
+  hide_personal : default!(Option<bool>, "false"),
+  context       : default!(Option<&str>, "''"),
+  kind          : default!(Option<mr_node_kind>, "null"),
+  lt            : default!(Option<f64>,  "null"),
+  lte           : default!(Option<f64>,  "null"),
+  gt            : default!(Option<f64>,  "null"),
+  gte           : default!(Option<f64>,  "null"),
+  index         : default!(Option<i32>,  "0"),
+  count         : default!(Option<i32>,  "16"),
+  order_by      : default!(Option<mr_score_order>, "'score_desc'"),
+  hide_negative : default!(Option<bool>, "false"),
+  min_abs_score : default!(Option<f64>,  "null")
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_score")>,
+  ErrorReport,
+> {
+  let response = score_pages(
+    context,
+    src,
+    hide_personal,
+    kind,
+    lt, lte,
+    gt, gte,
+    index,
+    count,
+    order_by,
+    hide_negative,
+    min_abs_score
+  )?;
+
+  let first_rank = index.unwrap_or(0) as i64 + 1;
+  return Ok(make_setof_score(context.unwrap_or(""), first_rank, response)?);
+}
+
+//  Sources per mr_scores_bulk request batch. Batches are sent as the
+//  executor reads the rows, so only one batch of scores is in memory.
+const BULK_CHUNK : usize = 1024;
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_scores_bulk(
+  srcs          : Option<Vec<String>>,
+  hide_personal : default!(Option<bool>, "false"),
+  context       : default!(Option<&str>, "''"),
+  kind          : default!(Option<mr_node_kind>, "null"),
+  lt            : default!(Option<f64>,  "null"),
+  lte           : default!(Option<f64>,  "null"),
+  gt            : default!(Option<f64>,  "null"),
+  gte           : default!(Option<f64>,  "null"),
+  index         : default!(Option<i32>,  "0"),
+  count         : default!(Option<i32>,  "16"),
+  order_by      : default!(Option<mr_score_order>, "'score_desc'"),
+  hide_negative : default!(Option<bool>, "false"),
+  min_abs_score : default!(Option<f64>,  "null")
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
+  ErrorReport,
+> {
+  let srcs = required(srcs, "srcs")?;
+
+  //  Build all payloads first, so bad arguments fail before anything
+  //  is sent.
+  let mut payloads = vec![];
+
+  for src in srcs.iter() {
+    payloads.push(scores_payload(
+      context,
+      Some(src),
+      hide_personal,
+      kind,
+      lt, lte,
+      gt, gte,
+      index,
+      count,
+      order_by,
+      hide_negative,
+      min_abs_score
+    )?);
+  }
+
+  let chunks : Vec<Vec<Vec<u8>>> =
+    payloads
+      .chunks(BULK_CHUNK)
+      .map(|x| x.to_vec())
+      .collect();
+
+  let response = chunks.into_iter().flat_map(move |chunk| {
+    let scores = (|| {
+      let (url, responses) = request_many(Route::Replica, &chunk, Some(recv_timeout_msec()))?;
+
+      let mut scores = vec![];
+
+      for (payload, msg) in chunk.iter().zip(responses.iter()) {
+        let response : Vec<(String, String, f64)> = decode(&url, payload, msg)?;
+
+        match reordered(order_by, min_abs_score) {
+          true  => scores.extend(reorder_scores(response, index, count, order_by, min_abs_score)),
+          false => scores.extend(response),
+        }
+      }
+
+      Ok::<_, MrError>(scores)
+    })();
+
+    match scores {
+      Ok(x)  => x,
+      Err(e) => e.report(),
+    }
+  });
+
+  return Ok(make_setof_edge(response)?);
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_graph(
+  src           : Option<&str>,
+  focus         : Option<&str>,
+  context       : default!(Option<&str>, "''"),
+  positive_only : default!(Option<bool>, "false"),
+  index         : default!(Option<i32>,  "0"),
+  count         : default!(Option<i32>,  "16")
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
+  ErrorReport,
+> {
+  let context       = context.unwrap_or("").to_string();
+  let ego           = required(src,   "src")?.to_string();
+  let focus         = required(focus, "focus")?.to_string();
+  let positive_only = positive_only.unwrap_or(false);
+  let index         = index.unwrap_or(0) as u32;
+  let count         = count.unwrap_or(i32::MAX) as u32;
+
+  let response = Pages::new(index, count, move |index, count| {
+    let args = (
+      ego.as_str(),
+      focus.as_str(),
+      positive_only,
+      index,
+      count
+    );
+
+    let payload = command_payload(CMD_GRAPH, &context, true, &args)?;
+    request(Route::Replica, payload, Some(recv_timeout_msec()))
+  })?;
+
+  return Ok(make_setof_edge(response)?);
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_nodelist(
+  context : default!(Option<&str>, "''")
+) -> Result<
+  SetOfIterator<'static, String>,
+  ErrorReport,
+> {
+  let context = context.unwrap_or("");
+
+  let payload = command_payload(CMD_NODE_LIST, context, true, &())?;
+
+  let response : Vec<_> = request(Route::Replica, payload, Some(recv_timeout_msec()))?;
+
+  let strings : Vec<String> =
+    response
+      .iter()
+      .map(|x : &(String,)| x.0.clone())
+      .collect();
+  return Ok(SetOfIterator::new(strings));
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_edgelist(
+  context : default!(Option<&str>, "''")
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
+  ErrorReport,
+> {
+  let context = context.unwrap_or("");
+
+  let response = edge_pages(Route::Replica, context)?;
+  return Ok(make_setof_edge(response)?);
+}
+
+//  Counters of the shared cache, zeros if it is disabled.
+
+#[pg_extern]
+fn mr_cache_stats() -> TableIterator<'static, (
+  name!(hits,          i64),
+  name!(misses,        i64),
+  name!(evictions,     i64),
+  name!(invalidations, i64),
+  name!(entries,       i64),
+  name!(slots,         i64),
+)> {
+  let (hits, misses, evictions, invalidations, entries, slots) =
+    shared_cache::stats().unwrap_or((0, 0, 0, 0, 0, 0));
+
+  return TableIterator::once((
+    hits          as i64,
+    misses        as i64,
+    evictions     as i64,
+    invalidations as i64,
+    entries       as i64,
+    slots         as i64,
+  ));
+}
+
+extension_sql!(r#"
+CREATE OR REPLACE VIEW mr_cache_status AS SELECT
+  *,
+  CASE WHEN hits + misses > 0
+    THEN hits::double precision / (hits + misses)
+  END AS hit_ratio
+  FROM mr_cache_stats();
+"#,
+  name     = "cache_status",
+  requires = [mr_cache_stats],
+);
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_connected(
+  src     : Option<&str>,
+  context : default!(Option<&str>, "''")
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_link")>,
+  ErrorReport,
+> {
+  let context = context.unwrap_or("");
+  let ego     = required(src, "src")?;
+
+  let payload  = command_payload(CMD_CONNECTED, context, true, &(ego))?;
+  let response = request(Route::Replica, payload, Some(recv_timeout_msec()))?;
+  return Ok(make_setof_link(response)?);
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_mutual_scores(
+  src     : Option<&str>,
+  context : default!(Option<&str>, "''")
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_mutual_score")>,
+  ErrorReport,
+> {
+  let ego     = required(src, "src")?;
+  let context = context.unwrap_or("");
+
+  let payload  = command_payload(CMD_MUTUAL_SCORES, context, true, &(ego))?;
+  let response = request(Route::Replica, payload, Some(recv_timeout_msec()))?;
+  return Ok(make_setof_mutual_score(ego, response)?);
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_get_new_edges_filter(
+  src : Option<&str>
+) -> Result<Vec<u8>, ErrorReport> {
+  let src = required(src, "src")?;
+
+  let payload  = command_payload(CMD_READ_NEW_EDGES_FILTER, "", true, &(src))?;
+  let response = request(Route::Primary, payload, Some(recv_timeout_msec()))?;
+  return Ok(response);
+}
+
+#[pg_extern]
+fn mr_sync(
+  timeout_msec : default!(Option<i32>, "6000000"),
+) -> Result<
+  &'static str,
+  ErrorReport
+> {
+  let timeout_msec = match timeout_msec { Some(x) => Some(x as u64), _ => None, };
+
+  let payload = command_payload(CMD_SYNC, "", true, &())?;
+
+  let _ : () = request(Route::Primary, payload, timeout_msec)?;
+  return Ok("Ok");
+}
+
+//  ================================================================
+//
+//    Mutable functions
+//
+//  ================================================================
+
+#[pg_extern]
+fn mr_log_level(
+  log_level : default!(Option<i32>, "1"),
+) -> Result<&'static str, ErrorReport> {
+  let log_level = log_level.unwrap_or(0);
+
+  let payload = command_payload(CMD_LOG_LEVEL, "", true, &(log_level as u32))?;
+
+  let _ : () = request(Route::Primary, payload, Some(recv_timeout_msec()))?;
+  return Ok("Ok");
+}
+
+#[pg_extern]
+fn mr_create_context(
+  context : Option<&str>
+) -> Result<&'static str, ErrorReport> {
+  let context = context.unwrap_or("");
+
+  let payload = command_payload(CMD_CREATE_CONTEXT, context, false, &())?;
+
+  let _ : () = write_request(payload, Some(recv_timeout_msec()))?;
+  return Ok("Ok");
+}
+
+#[pg_extern]
+fn mr_put_edge(
+  src     : Option<&str>,
+  dst     : Option<&str>,
+  weight  : Option<f64>,
+  context : default!(Option<&str>, "''")
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
+  ErrorReport,
+> {
+  let context = context.unwrap_or("");
+  let src     = required(src,    "src")?;
+  let dest    = required(dst,    "dst")?;
+  let weight  = required(weight, "weight")?;
+
+  write_command(put_edge_payload(context, src, dest, weight)?)?;
+  return Ok(make_setof_edge(vec![(src.to_string(), dest.to_string(), weight)])?);
+}
+
+#[pg_extern]
+fn mr_put_edges(
+  src     : Option<Vec<Option<String>>>,
+  dst     : Option<Vec<Option<String>>>,
+  weight  : Option<Vec<Option<f64>>>,
+  context : default!(Option<&str>, "''")
+) -> Result<i64, ErrorReport> {
+  let context = context.unwrap_or("");
+  let src     = required(src,    "src")?;
+  let dest    = required(dst,    "dst")?;
+  let weight  = required(weight, "weight")?;
+
+  if src.len() != dest.len() || src.len() != weight.len() {
+    return Err(MrError::InvalidArgument("src, dst and weight should have the same length!".to_string()).into());
+  }
+
+  //  Requests are pipelined and may be processed in any order,
+  //  so only the last weight for each edge is sent.
+  let mut edges : Vec<(&str, &str, f64)>       = vec![];
+  let mut index : HashMap<(&str, &str), usize> = HashMap::new();
+
+  for ((src, dest), weight) in src.iter().zip(dest.iter()).zip(weight.iter()) {
+    let src    = required(src .as_deref(), "src element")?;
+    let dest   = required(dest.as_deref(), "dst element")?;
+    let weight = required(*weight,         "weight element")?;
+
+    match index.get(&(src, dest)) {
+      Some(&n) => edges[n].2 = weight,
+      None     => {
+        index.insert((src, dest), edges.len());
+        edges.push((src, dest, weight));
+      },
+    }
+  }
+
+  let mut payloads = vec![];
+
+  for (src, dest, weight) in edges {
+    payloads.push(put_edge_payload(context, src, dest, weight)?);
+  }
+
+  let applied = write_commands(payloads)?;
+  return Ok(applied as i64);
+}
+
+#[pg_extern]
+fn mr_delete_edge(
+  src     : Option<&str>,
+  dst     : Option<&str>,
+  context : default!(Option<&str>, "''")
+) -> Result<&'static str, ErrorReport> {
+  let context = context.unwrap_or("");
+  let ego     = required(src, "src")?;
+  let target  = required(dst, "dst")?;
+
+  write_command(delete_edge_payload(context, ego, target)?)?;
+  return Ok("Ok");
+}
+
+#[pg_extern]
+fn mr_delete_node(
+  src     : Option<&str>,
+  context : default!(Option<&str>, "''")
+) -> Result<&'static str, ErrorReport> {
+  let context = context.unwrap_or("");
+  let ego     = required(src, "src")?;
+
+  write_command(delete_node_payload(context, ego)?)?;
+  return Ok("Ok");
+}
+
+#[pg_extern]
+fn mr_set_new_edges_filter(
+  src    : Option<&str>,
+  filter : Option<Vec<u8>>,
+) -> Result<&'static str, ErrorReport> {
+  let src    = required(src,    "src")?;
+  let filter = required(filter, "filter")?;
+
+  let payload = command_payload(CMD_WRITE_NEW_EDGES_FILTER, "", false, &(src, filter))?;
+
+  let _ : () = write_request(payload, Some(recv_timeout_msec()))?;
+  return Ok("Ok");
+}
+
+#[pg_extern]
+fn mr_fetch_new_edges(
+  src    : Option<&str>,
+  prefix : default!(Option<&str>, "''"),
+) -> Result<
+  SetOfIterator<'static, pgrx::composite_type!('static, "mr_t_edge")>,
+  ErrorReport,
+> {
+  let src    = required(src, "src")?;
+  let prefix = mr_node_kind::parse(prefix)?.map(|x| x.prefix()).unwrap_or("");
+
+  let payload  = command_payload(CMD_FETCH_NEW_EDGES, "", true, &(src, prefix))?;
+  let response = write_request(payload, Some(recv_timeout_msec()))?;
+  return Ok(make_setof_edge_for_src(src, response)?);
+}
+
+#[pg_extern]
+fn mr_reset() -> Result<
+  &'static str,
+  ErrorReport,
+> {
+  let payload = command_payload(CMD_RESET, "", false, &())?;
+
+  let _ : () = write_request(payload, Some(recv_timeout_msec()))?;
+  return Ok("Ok");
+}
+
+#[pg_extern]
+fn mr_zerorec(
+  blocking     : default!(Option<bool>, "true"),
+  timeout_msec : default!(Option<i32>,  "6000000")
+) -> Result<
+  &'static str,
+  ErrorReport,
+> {
+  let blocking     = blocking.unwrap_or(true);
+  let timeout_msec = match timeout_msec { Some(x) => Some(x as u64), _ => None, };
+
+  let payload = command_payload(CMD_RECALCULATE_ZERO, "", blocking, &())?;
+
+  let _ : () = write_request(payload, timeout_msec)?;
+  return Ok("Ok");
+}
+
+//  ================================================================
+//
+//    Jobs
+//
+//  ================================================================
+
+//  Long-running service commands are tracked in the mr_job table, so any
+//  session can check on them. Each job is run by a background worker,
+//  which commits the job row before sending the command, so a job is
+//  never lost to a rollback of the caller. The service has no job ids
+//  of its own: a job is done once the primary that accepted the command
+//  answers a sync command, which waits until its queue has drained.
+//
+//  Job states:
+//    queued  - the row is committed, the command is not sent yet
+//    running - the command was accepted by the primary at url
+//    done    - that primary confirmed it processed the command
+//    failed  - the command was rejected, or the primary was lost
+//              before it confirmed
+
+extension_sql!(r#"
+CREATE TABLE IF NOT EXISTS mr_job (
+  id          bigserial   PRIMARY KEY,
+  kind        text        NOT NULL,
+  state       text        NOT NULL DEFAULT 'queued',
+  url         text,
+  started_at  timestamptz NOT NULL DEFAULT clock_timestamp(),
+  finished_at timestamptz,
+  error       text
+);
+
+ALTER TABLE mr_job ADD COLUMN IF NOT EXISTS url text;
+ALTER TABLE mr_job ALTER COLUMN state SET DEFAULT 'queued';
+"#,
+  name = "jobs",
+);
+
+//  Delay between checks of the job row by waiting sessions.
+const JOB_POLL_MSEC : u64 = 100;
+
+//  How long the worker waits for one sync response before checking
+//  for shutdown and asking again.
+const JOB_SYNC_MSEC : u64 = 1000;
+
+fn int8_arg(x : i64) -> (PgOid, Option<pg_sys::Datum>) {
+  (PgBuiltInOids::INT8OID.oid(), x.into_datum())
+}
+
+fn update_job(id : i64, state : &str, url : Option<&str>, error : Option<&str>) -> Result<(), MrError> {
+  Spi::run_with_args(
+    "UPDATE mr_job
+        SET state       = $2,
+            url         = coalesce($3, url),
+            error       = $4,
+            finished_at = CASE WHEN $2 IN ('done', 'failed') THEN clock_timestamp() END
+      WHERE id = $1",
+    Some(vec![
+      int8_arg(id),
+      text_arg(state),
+      (PgBuiltInOids::TEXTOID.oid(), url.into_datum()),
+      (PgBuiltInOids::TEXTOID.oid(), error.into_datum()),
+    ]),
+  )?;
+  return Ok(());
+}
+
+//  Job state, None if the row is not visible. Read with a new snapshot
+//  each time, so a waiting session sees the updates of the worker.
+
+fn job_state(id : i64) -> Result<Option<String>, MrError> {
+  let state = Spi::connect(|mut client| {
+    client
+      .update("SELECT (SELECT state FROM mr_job WHERE id = $1)", Some(1), Some(vec![int8_arg(id)]))?
+      .first()
+      .get_one::<String>()
+  })?;
+  return Ok(state);
+}
+
+//  Polls the job state until stop returns true, or for up to
+//  timeout_msec. Returns the last state seen.
+
+fn wait_job(
+  id           : i64,
+  timeout_msec : Option<u64>,
+  stop         : impl Fn(Option<&str>) -> bool,
+) -> Result<Option<String>, MrError> {
+  let started = std::time::Instant::now();
+
+  loop {
+    let state = job_state(id)?;
+
+    if stop(state.as_deref()) {
+      return Ok(state);
+    }
+    if timeout_msec.map_or(false, |x| started.elapsed() >= Duration::from_millis(x)) {
+      return Ok(state);
+    }
+
+    std::thread::sleep(Duration::from_millis(JOB_POLL_MSEC));
+    check_for_interrupts!();
+  }
+}
+
+fn finished(state : Option<&str>) -> bool {
+  matches!(state, Some("done") | Some("failed"))
+}
+
+//  Runs one job. Started by mr_zerorec_start with the job id as the
+//  argument, and the database and service URL as the extra data, since
+//  the worker does not see the settings of the calling session.
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn mr_job_worker(arg : pg_sys::Datum) {
+  BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGTERM);
+
+  let id = unsafe { i64::from_datum(arg, false) }.unwrap_or(0);
+
+  let (database, urls) = match BackgroundWorker::get_extra().split_once('\n') {
+    Some((database, urls)) => (database.to_string(), urls.to_string()),
+    None                   => return,
+  };
+
+  BackgroundWorker::connect_worker_to_spi(Some(&database), None);
+
+  if let Ok(urls) = std::ffi::CString::new(urls) {
+    unsafe {
+      pg_sys::SetConfigOption(
+        b"pgmer2.service_url\0".as_ptr() as *const std::ffi::c_char,
+        urls.as_ptr(),
+        pg_sys::GucContext_PGC_USERSET,
+        pg_sys::GucSource_PGC_S_SESSION,
+      );
+    }
+  }
+
+  if let Err(e) = run_job(id) {
+    log!("pgmer2: job {} failed: {}", id, e);
+    let _ = BackgroundWorker::transaction(|| update_job(id, "failed", None, Some(&e.to_string())));
+  }
+}
+
+fn run_job(id : i64) -> Result<(), MrError> {
+  BackgroundWorker::transaction(|| {
+    Spi::run_with_args(
+      "INSERT INTO mr_job (id, kind, state) VALUES ($1, 'zerorec', 'queued')",
+      Some(vec![int8_arg(id)]),
+    )
+  })?;
+
+  let payload = command_payload(CMD_RECALCULATE_ZERO, "", false, &())?;
+
+  let (url, msg) = request_raw(Route::Primary, payload.clone(), Some(recv_timeout_msec()))?;
+  cache::invalidate();
+
+  if let Err(e) = decode::<()>(&url, &payload, &msg) {
+    return BackgroundWorker::transaction(|| update_job(id, "failed", Some(&url), Some(&e.to_string())));
+  }
+
+  BackgroundWorker::transaction(|| update_job(id, "running", Some(&url), None))?;
+
+  //  Only the primary that accepted the command can confirm it. Another
+  //  primary, e.g. after failover, doesn't know about it.
+  let sync = command_payload(CMD_SYNC, "", true, &())?;
+
+  loop {
+    if BackgroundWorker::sigterm_received() {
+      return Err(MrError::Internal("the job worker was stopped before the service confirmed the job".to_string()));
+    }
+
+    //  Sync only waits, so it is safe to resend.
+    match request_endpoint(&url, &sync, Some(JOB_SYNC_MSEC), true) {
+      Ok(msg) => {
+        let (state, error) = match decode::<()>(&url, &sync, &msg) {
+          Ok(())  => ("done", None),
+          Err(e)  => ("failed", Some(e.to_string())),
+        };
+        return BackgroundWorker::transaction(|| update_job(id, state, None, error.as_deref()));
+      },
+      Err(nng::Error::TimedOut) => continue,
+      Err(e)                    => return Err(transport_error(&url, &sync, Some(JOB_SYNC_MSEC), e)),
+    }
+  }
+}
+
+#[pg_extern]
+fn mr_zerorec_start() -> Result<i64, ErrorReport> {
+  //  The job row is committed by the worker, and would not be visible
+  //  to a transaction snapshot taken before.
+  if unsafe { pg_sys::XactIsoLevel } >= pg_sys::XACT_REPEATABLE_READ as i32 {
+    return Err(MrError::InvalidArgument("mr_zerorec_start requires READ COMMITTED isolation".to_string()).into());
+  }
+
+  let id : Option<i64> = Spi::get_one("SELECT nextval('mr_job_id_seq')").map_err(MrError::from)?;
+  let id = required(id, "job id")?;
+
+  let database = unsafe { CStr::from_ptr(pg_sys::get_database_name(pg_sys::MyDatabaseId)) };
+  let extra    = format!("{}\n{}", database.to_string_lossy(), service_url());
+
+  if extra.len() >= pg_sys::BGW_EXTRALEN as usize {
+    return Err(MrError::InvalidArgument("pgmer2.service_url is too long for a background job".to_string()).into());
+  }
+
+  let worker = BackgroundWorkerBuilder::new("pgmer2 job")
+    .set_function("mr_job_worker")
+    .set_library("pgmer2")
+    .set_argument(id.into_datum())
+    .set_extra(&extra)
+    .enable_spi_access()
+    .set_notify_pid(unsafe { pg_sys::MyProcPid })
+    .load_dynamic()
+    .map_err(|_| MrError::Internal("could not start a job worker, check max_worker_processes".to_string()))?;
+
+  worker
+    .wait_for_startup()
+    .map_err(|_| MrError::Internal("the job worker did not start".to_string()))?;
+
+  //  Return once the row is committed, so the job can be checked on
+  //  right away.
+  if wait_job(id, Some(recv_timeout_msec()), |x| x.is_some())?.is_none() {
+    return Err(MrError::Internal(format!("job {} was not recorded by the worker", id)).into());
+  }
+
+  return Ok(id);
+}
+
+//  There is no progress column: the service doesn't report the
+//  progress of a recalculation, only when its queue has drained.
+
+#[pg_extern]
+fn mr_job_status(
+  id : Option<i64>,
+) -> Result<
+  TableIterator<'static, (
+    name!(id,          i64),
+    name!(kind,        String),
+    name!(state,       String),
+    name!(url,         Option<String>),
+    name!(started_at,  Option<TimestampWithTimeZone>),
+    name!(finished_at, Option<TimestampWithTimeZone>),
+    name!(error,       Option<String>),
+  )>,
+  ErrorReport,
+> {
+  let id = required(id, "id")?;
+
+  //  With a new snapshot, see job_state.
+  let row = Spi::connect(|mut client| {
+    let table = client
+      .update(
+        "SELECT kind, state, url, started_at, finished_at, error FROM mr_job WHERE id = $1",
+        Some(1),
+        Some(vec![int8_arg(id)]),
+      )?;
+
+    if table.len() == 0 {
+      return Err(MrError::InvalidArgument(format!("job {} does not exist", id)));
+    }
+
+    let row = table.first();
+
+    let kind  : Option<String> = row.get(1)?;
+    let state : Option<String> = row.get(2)?;
+    let kind  = kind.unwrap_or_default();
+
+    Ok::<_, MrError>((
+      id,
+      kind,
+      state.unwrap_or_default(),
+      row.get(3)?,
+      row.get(4)?,
+      row.get(5)?,
+      row.get(6)?,
+    ))
+  })?;
+
+  return Ok(TableIterator::new(vec![row]));
+}
+
+#[pg_extern]
+fn mr_job_wait(
+  id           : Option<i64>,
+  timeout_msec : default!(Option<i32>, "6000000"),
+) -> Result<String, ErrorReport> {
+  let id           = required(id, "id")?;
+  let timeout_msec = match timeout_msec { Some(x) => Some(x.max(0) as u64), _ => None, };
+
+  match wait_job(id, timeout_msec, finished)? {
+    Some(state) => Ok(state),
+    None        => Err(MrError::InvalidArgument(format!("job {} does not exist", id)).into()),
+  }
+}
+
+//  ================================================================
+//
+//    Table sync
+//
+//  ================================================================
+
+//  One trigger per table, so a table syncs to one graph context, or to
+//  the contexts given by its context column.
+const SYNC_TRIGGER : &str = "mr_graph_sync";
+
+fn text_arg(s : &str) -> (PgOid, Option<pg_sys::Datum>) {
+  (PgBuiltInOids::TEXTOID.oid(), s.into_datum())
+}
+
+//  Builds an SQL statement with format() and runs it.
+
+fn run_formatted(
+  query : &str,
+  args  : Vec<(PgOid, Option<pg_sys::Datum>)>,
+) -> Result<(), MrError> {
+  let sql : Option<String> = Spi::get_one_with_args(query, args)?;
+  let sql = required(sql, "formatted statement")?;
+  Spi::run(&sql)?;
+  return Ok(());
+}
+
+fn column_type(
+  table  : &str,
+  column : &str,
+) -> Result<String, MrError> {
+  let t : Option<String> = Spi::get_one_with_args(
+    "SELECT format_type(atttypid, NULL)
+       FROM pg_attribute
+      WHERE attrelid = $1::regclass
+        AND attname  = $2
+        AND attnum   > 0
+        AND NOT attisdropped",
+    vec![text_arg(table), text_arg(column)],
+  )?;
+
+  match t {
+    Some(t) => Ok(t),
+    None    => Err(MrError::InvalidArgument(format!("column {} does not exist in {}", column, table))),
+  }
+}
+
+fn check_column_type(
+  table    : &str,
+  column   : &str,
+  expected : &[&str],
+) -> Result<(), MrError> {
+  let t = column_type(table, column)?;
+
+  if !expected.contains(&t.as_str()) {
+    return Err(MrError::InvalidArgument(format!(
+      "column {} of {} should be {}, not {}",
+      column, table, expected.join(" or "), t
+    )));
+  }
+
+  return Ok(());
+}
+
+//  Trigger arguments:
+//    src column, dst column, weight column, context column or '',
+//    context to use when there is no context column.
+
+fn trigger_edge(
+  tuple : &PgHeapTuple<'_, AllocatedByPostgres>,
+  args  : &[String],
+) -> Result<Option<(String, String, f64, String)>, MrError> {
+  if args.len() != 5 {
+    return Err(MrError::InvalidArgument("mr_table_trigger expects 5 arguments".to_string()));
+  }
+
+  let get_text = |name : &str| -> Result<Option<String>, MrError> {
+    tuple.get_by_name(name).map_err(|e| MrError::Internal(e.to_string()))
+  };
+
+  let src    : Option<String> = get_text(&args[0])?;
+  let dst    : Option<String> = get_text(&args[1])?;
+  let weight : Option<f64>    = tuple.get_by_name(&args[2]).map_err(|e| MrError::Internal(e.to_string()))?;
+
+  let context : Option<String> =
+    if args[3].is_empty() {
+      None
+    } else {
+      get_text(&args[3])?
+    };
+
+  let context = context.unwrap_or(args[4].clone());
+
+  //  Rows with null columns don't map to an edge.
+  match (src, dst, weight) {
+    (Some(src), Some(dst), Some(weight)) => Ok(Some((src, dst, weight, context))),
+    _                                    => Ok(None),
+  }
+}
+
+#[pg_trigger]
+fn mr_table_trigger<'a>(
+  trigger : &'a PgTrigger<'a>,
+) -> Result<
+  Option<PgHeapTuple<'a, AllocatedByPostgres>>,
+  ErrorReport,
+> {
+  let args = trigger.extra_args().map_err(|e| MrError::Internal(e.to_string()))?;
+
+  let old = match trigger.old() {
+    Some(x) => trigger_edge(&x, &args)?,
+    None    => None,
+  };
+
+  let new = match trigger.new() {
+    Some(x) => trigger_edge(&x, &args)?,
+    None    => None,
+  };
+
+  let mut payloads = vec![];
+
+  match (old, new) {
+    (Some(old), Some(new)) if (&old.0, &old.1, &old.3) == (&new.0, &new.1, &new.3) => {
+      if old.2 != new.2 {
+        payloads.push(put_edge_payload(&new.3, &new.0, &new.1, new.2)?);
+      }
+    },
+
+    (old, new) => {
+      if let Some((src, dst, _, context)) = old {
+        payloads.push(delete_edge_payload(&context, &src, &dst)?);
+      }
+      if let Some((src, dst, weight, context)) = new {
+        payloads.push(put_edge_payload(&context, &src, &dst, weight)?);
+      }
+    },
+  }
+
+  //  The row change may still roll back.
+  let _ = write_commands_on_commit(payloads)?;
+  return Ok(None);
+}
+
+//  Edges of a source table as (src, dst, weight, context). Rows with
+//  null columns are skipped, a null context means the given one.
+
+fn source_edges(
+  source         : &str,
+  src_column     : &str,
+  dst_column     : &str,
+  weight_column  : &str,
+  context_column : &str,
+  context        : &str,
+) -> Result<Vec<(String, String, f64, String)>, MrError> {
+  let select : Option<String> = Spi::get_one_with_args(
+    "SELECT format(
+       'SELECT %I::text, %I::text, %I::float8, %s FROM %s',
+       $1, $2, $3,
+       CASE WHEN $4 = '' THEN 'NULL::text' ELSE format('%I::text', $4) END,
+       $5::regclass
+     )",
+    vec![
+      text_arg(src_column),
+      text_arg(dst_column),
+      text_arg(weight_column),
+      text_arg(context_column),
+      text_arg(source),
+    ],
+  )?;
+
+  let select = required(select, "formatted statement")?;
+
+  Spi::connect(|client| {
+    let mut edges = vec![];
+
+    for row in client.select(&select, None, None)? {
+      let src     : Option<String> = row.get(1)?;
+      let dst     : Option<String> = row.get(2)?;
+      let weight  : Option<f64>    = row.get(3)?;
+      let ctx     : Option<String> = row.get(4)?;
+
+      match (src, dst, weight) {
+        (Some(src), Some(dst), Some(weight)) => {
+          edges.push((src, dst, weight, ctx.unwrap_or(context.to_string())));
+        },
+        _ => {},
+      }
+    }
+
+    Ok::<_, MrError>(edges)
+  })
+}
+
+#[pg_extern]
+fn mr_attach_table(
+  source         : Option<&str>,
+  src_column     : default!(Option<&str>, "'src'"),
+  dst_column     : default!(Option<&str>, "'dst'"),
+  weight_column  : default!(Option<&str>, "'weight'"),
+  context_column : default!(Option<&str>, "null"),
+  context        : default!(Option<&str>, "''"),
+  backfill       : default!(Option<bool>, "true"),
+) -> Result<i64, ErrorReport> {
+  let source         = required(source,        "source")?;
+  let src_column     = required(src_column,    "src_column")?;
+  let dst_column     = required(dst_column,    "dst_column")?;
+  let weight_column  = required(weight_column, "weight_column")?;
+  let context_column = context_column.unwrap_or("");
+  let context        = context.unwrap_or("");
+  let backfill       = backfill.unwrap_or(true);
+
+  let text  = ["text", "character varying"];
+  let float = ["double precision"];
+
+  check_column_type(source, src_column,    &text)?;
+  check_column_type(source, dst_column,    &text)?;
+  check_column_type(source, weight_column, &float)?;
+  if !context_column.is_empty() {
+    check_column_type(source, context_column, &text)?;
+  }
+
+  //  The backfill must read every row written before the trigger exists.
+  //  The lock waits for running writers, and blocks new ones until the
+  //  transaction ends, so they fire the trigger after the backfill is
+  //  sent. A snapshot taken before the lock could miss rows, so the
+  //  transaction snapshot of repeatable read is not allowed.
+  if backfill && unsafe { pg_sys::XactIsoLevel } >= pg_sys::XACT_REPEATABLE_READ as i32 {
+    return Err(MrError::InvalidArgument("mr_attach_table with backfill requires READ COMMITTED isolation".to_string()).into());
+  }
+
+  run_formatted(
+    "SELECT format('LOCK TABLE %s IN SHARE ROW EXCLUSIVE MODE', $1::regclass)",
+    vec![text_arg(source)],
+  )?;
+
+  run_formatted(
+    "SELECT format(
+       'CREATE TRIGGER %I AFTER INSERT OR UPDATE OR DELETE ON %s '
+       'FOR EACH ROW EXECUTE FUNCTION mr_table_trigger(%L, %L, %L, %L, %L)',
+       $1, $2::regclass, $3, $4, $5, $6, $7
+     )",
+    vec![
+      text_arg(SYNC_TRIGGER),
+      text_arg(source),
+      text_arg(src_column),
+      text_arg(dst_column),
+      text_arg(weight_column),
+      text_arg(context_column),
+      text_arg(context),
+    ],
+  )?;
+
+  if !backfill {
+    return Ok(0);
+  }
+
+  let edges = source_edges(
+    source,
+    src_column,
+    dst_column,
+    weight_column,
+    context_column,
+    context
+  )?;
+
+  let mut payloads = vec![];
+
+  for (src, dst, weight, ctx) in edges {
+    payloads.push(put_edge_payload(&ctx, &src, &dst, weight)?);
+  }
+
+  let applied = write_commands_on_commit(payloads)?;
+  return Ok(applied as i64);
+}
+
+#[pg_extern]
+fn mr_detach_table(
+  source : Option<&str>,
+) -> Result<&'static str, ErrorReport> {
+  let source = required(source, "source")?;
+
+  run_formatted(
+    "SELECT format('DROP TRIGGER IF EXISTS %I ON %s', $1, $2::regclass)",
+    vec![text_arg(SYNC_TRIGGER), text_arg(source)],
+  )?;
+
+  return Ok("Ok");
+}
+
+//  ================================================================
+//
+//    Reconciliation
+//
+//  ================================================================
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum EdgeDiff {
+  //  In the table, not in the graph.
+  Missing,
+  //  In the graph, not in the table.
+  Extra,
+  WeightMismatch,
+}
+
+impl EdgeDiff {
+  fn name(self) -> &'static str {
+    match self {
+      EdgeDiff::Missing        => "missing",
+      EdgeDiff::Extra          => "extra",
+      EdgeDiff::WeightMismatch => "weight_mismatch",
+    }
+  }
+}
+
+//  Differences between a source table and a graph context as
+//  (src, dst, diff, table weight, graph weight), ordered by src and dst.
+
+fn diff_edges(
+  route          : Route,
+  source         : &str,
+  context        : &str,
+  src_column     : &str,
+  dst_column     : &str,
+  weight_column  : &str,
+  context_column : &str,
+) -> Result<Vec<(String, String, EdgeDiff, Option<f64>, Option<f64>)>, MrError> {
+  let table : HashMap<(String, String), f64> =
+    source_edges(source, src_column, dst_column, weight_column, context_column, context)?
+      .into_iter()
+      .filter(|(_, _, _, ctx)| ctx == context)
+      .map(|(src, dst, weight, _)| ((src, dst), weight))
+      .collect();
+
+  let graph : HashMap<(String, String), f64> =
+    fetch_edges(route, context)?
+      .into_iter()
+      .map(|(src, dst, weight)| ((src, dst), weight))
+      .collect();
+
+  let mut diff = vec![];
+
+  for ((src, dst), weight) in table.iter() {
+    match graph.get(&(src.clone(), dst.clone())) {
+      None =>
+        diff.push((src.clone(), dst.clone(), EdgeDiff::Missing, Some(*weight), None)),
+      Some(x) if x != weight =>
+        diff.push((src.clone(), dst.clone(), EdgeDiff::WeightMismatch, Some(*weight), Some(*x))),
+      _ => {},
+    }
+  }
+
+  for ((src, dst), weight) in graph.iter() {
+    if !table.contains_key(&(src.clone(), dst.clone())) {
+      diff.push((src.clone(), dst.clone(), EdgeDiff::Extra, None, Some(*weight)));
+    }
+  }
+
+  diff.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+  return Ok(diff);
+}
+
+#[pg_extern]
+fn mr_diff(
+  source         : Option<&str>,
+  context        : default!(Option<&str>, "''"),
+  src_column     : default!(Option<&str>, "'src'"),
+  dst_column     : default!(Option<&str>, "'dst'"),
+  weight_column  : default!(Option<&str>, "'weight'"),
+  context_column : default!(Option<&str>, "null"),
+) -> Result<
+  TableIterator<'static, (
+    name!(src,          String),
+    name!(dst,          String),
+    name!(status,       String),
+    name!(table_weight, Option<f64>),
+    name!(graph_weight, Option<f64>),
+  )>,
+  ErrorReport,
+> {
+  let source         = required(source,        "source")?;
+  let context        = context.unwrap_or("");
+  let src_column     = required(src_column,    "src_column")?;
+  let dst_column     = required(dst_column,    "dst_column")?;
+  let weight_column  = required(weight_column, "weight_column")?;
+  let context_column = context_column.unwrap_or("");
+
+  let diff = diff_edges(
+    Route::Replica,
+    source,
+    context,
+    src_column,
+    dst_column,
+    weight_column,
+    context_column
+  )?;
+
+  let rows : Vec<_> =
+    diff
+      .into_iter()
+      .map(|(src, dst, status, table_weight, graph_weight)| {
+        (src, dst, status.name().to_string(), table_weight, graph_weight)
+      })
+      .collect();
+
+  return Ok(TableIterator::new(rows));
+}
+
+#[pg_extern]
+fn mr_reconcile(
+  source         : Option<&str>,
+  context        : default!(Option<&str>, "''"),
+  dry_run        : default!(Option<bool>, "false"),
+  src_column     : default!(Option<&str>, "'src'"),
+  dst_column     : default!(Option<&str>, "'dst'"),
+  weight_column  : default!(Option<&str>, "'weight'"),
+  context_column : default!(Option<&str>, "null"),
+) -> Result<
+  TableIterator<'static, (
+    name!(added,   i64),
+    name!(updated, i64),
+    name!(deleted, i64),
+    name!(dry_run, bool),
+  )>,
+  ErrorReport,
+> {
+  let source         = required(source,        "source")?;
+  let context        = context.unwrap_or("");
+  let dry_run        = dry_run.unwrap_or(false);
+  let src_column     = required(src_column,    "src_column")?;
+  let dst_column     = required(dst_column,    "dst_column")?;
+  let weight_column  = required(weight_column, "weight_column")?;
+  let context_column = context_column.unwrap_or("");
+
+  //  Read from the primary, replicas may lag behind.
+  let diff = diff_edges(
+    Route::Primary,
+    source,
+    context,
+    src_column,
+    dst_column,
+    weight_column,
+    context_column
+  )?;
+
+  let mut added    = 0;
+  let mut updated  = 0;
+  let mut deleted  = 0;
+  let mut payloads = vec![];
+
+  for (src, dst, status, table_weight, _) in diff {
+    match (status, table_weight) {
+      (EdgeDiff::Missing, Some(weight)) => {
+        added += 1;
+        payloads.push(put_edge_payload(context, &src, &dst, weight)?);
+      },
+      (EdgeDiff::WeightMismatch, Some(weight)) => {
+        updated += 1;
+        payloads.push(put_edge_payload(context, &src, &dst, weight)?);
+      },
+      (EdgeDiff::Extra, _) => {
+        deleted += 1;
+        payloads.push(delete_edge_payload(context, &src, &dst)?);
+      },
+      _ => {},
+    }
+  }
+
+  if !dry_run {
+    let _ = write_chunked(payloads)?;
+  }
+
+  return Ok(TableIterator::new(vec![(added, updated, deleted, dry_run)]));
+}
+
+//  ================================================================
+//
+//    Snapshots
+//
+//  ================================================================
+
+//  Snapshot layout, encoded with MessagePack:
+//    (magic, version, [(context, [(src, dst, weight)])], [(user, new edges filter)])
+//
+//  The default context is the sum of all contexts, so it is never
+//  exported or imported. Restoring the named contexts restores it.
+
+const SNAPSHOT_MAGIC   : &str  = "pgmer2-snapshot";
+const SNAPSHOT_VERSION : u32   = 1;
+
+//  Edges sent per batch on import.
+const IMPORT_CHUNK     : usize = 10000;
+
+type Snapshot = (
+  String,
+  u32,
+  Vec<(String, Vec<(String, String, f64)>)>,
+  Vec<(String, Vec<u8>)>,
+);
+
+//  New edges filters of the given users, skipping empty ones.
+
+fn fetch_filters(users : Vec<String>) -> Result<Vec<(String, Vec<u8>)>, MrError> {
+  if users.is_empty() {
+    return Ok(vec![]);
+  }
+
+  let mut payloads = vec![];
+
+  for user in users.iter() {
+    payloads.push(command_payload(CMD_READ_NEW_EDGES_FILTER, "", true, &(user))?);
+  }
+
+  let (url, responses) = request_many(Route::Primary, &payloads, Some(recv_timeout_msec()))?;
+
+  let mut filters = vec![];
+
+  for ((user, payload), msg) in users.into_iter().zip(payloads.iter()).zip(responses.iter()) {
+    let filter : Vec<u8> = decode(&url, payload, msg)?;
+    if !filter.is_empty() {
+      filters.push((user, filter));
+    }
+  }
+
+  return Ok(filters);
+}
+
+fn export_snapshot(contexts : Vec<String>) -> Result<Vec<u8>, MrError> {
+  let mut graph = vec![];
+  let mut users = vec![];
+  let mut seen  = HashSet::new();
+
+  for context in contexts {
+    if context.is_empty() {
+      continue;
+    }
+
+    let edges = fetch_edges(Route::Replica, &context)?;
+
+    for (src, _, _) in edges.iter() {
+      if mr_node_kind::of(src) == Some(mr_node_kind::U) && seen.insert(src.clone()) {
+        users.push(src.clone());
+      }
+    }
+
+    graph.push((context, edges));
+  }
+
+  let snapshot : Snapshot = (
+    SNAPSHOT_MAGIC.to_string(),
+    SNAPSHOT_VERSION,
+    graph,
+    fetch_filters(users)?,
+  );
+
+  return Ok(rmp_serde::to_vec(&snapshot)?);
+}
+
+fn decode_snapshot(data : &[u8]) -> Result<Snapshot, MrError> {
+  let snapshot : Snapshot = match rmp_serde::from_slice(data) {
+    Ok(x)  => x,
+    Err(_) => return Err(MrError::InvalidArgument("not a pgmer2 snapshot".to_string())),
+  };
+
+  if snapshot.0 != SNAPSHOT_MAGIC {
+    return Err(MrError::InvalidArgument("not a pgmer2 snapshot".to_string()));
+  }
+
+  if snapshot.1 != SNAPSHOT_VERSION {
+    return Err(MrError::InvalidArgument(format!(
+      "unsupported snapshot version {}, expected {}",
+      snapshot.1, SNAPSHOT_VERSION
+    )));
+  }
+
+  return Ok(snapshot);
+}
+
+fn write_chunked(payloads : Vec<Vec<u8>>) -> Result<usize, MrError> {
+  let mut applied = 0;
+
+  for chunk in payloads.chunks(IMPORT_CHUNK) {
+    applied += write_commands(chunk.to_vec())?;
+  }
+
+  return Ok(applied);
+}
+
+#[pg_extern]
+fn mr_export(
+  contexts : default!(Option<Vec<String>>, "null"),
+) -> Result<Vec<u8>, ErrorReport> {
+  let contexts = match contexts {
+    Some(x) => x,
+    None    => return Err(MrError::InvalidArgument("contexts can't be listed by this service, pass them explicitly".to_string()).into()),
+  };
+
+  return Ok(export_snapshot(contexts)?);
+}
+
+#[pg_extern]
+fn mr_import(
+  snapshot : Option<Vec<u8>>,
+  mode     : default!(Option<&str>, "'merge'"),
+) -> Result<i64, ErrorReport> {
+  let snapshot = required(snapshot, "snapshot")?;
+  let mode     = mode.unwrap_or("merge");
+
+  let replace = match mode {
+    "merge"   => false,
+    "replace" => true,
+    _         => return Err(MrError::InvalidArgument(format!("unknown mode '{}', expected 'replace' or 'merge'", mode)).into()),
+  };
+
+  let (_, _, graph, filters) = decode_snapshot(&snapshot)?;
+
+  let mut imported = 0;
+
+  //  Contexts are not created with CMD_CREATE_CONTEXT, which would copy
+  //  the default context into them. Putting an edge creates the context.
+  for (context, edges) in graph {
+    if context.is_empty() {
+      continue;
+    }
+
+    //  Replace only drops edges of the contexts in the snapshot.
+    if replace {
+      let keep : HashSet<(&str, &str)> =
+        edges.iter().map(|(src, dst, _)| (src.as_str(), dst.as_str())).collect();
+
+      let mut deletes = vec![];
+
+      //  Read from the primary, replicas may lag behind.
+      for (src, dst, _) in fetch_edges(Route::Primary, &context)? {
+        if !keep.contains(&(src.as_str(), dst.as_str())) {
+          deletes.push(delete_edge_payload(&context, &src, &dst)?);
+        }
+      }
+
+      let _ = write_chunked(deletes)?;
+    }
+
+    let mut puts = vec![];
+
+    for (src, dst, weight) in edges.iter() {
+      puts.push(put_edge_payload(&context, src, dst, *weight)?);
+    }
+
+    imported += write_chunked(puts)?;
+  }
+
+  for (user, filter) in filters {
+    let payload = command_payload(CMD_WRITE_NEW_EDGES_FILTER, "", false, &(user, filter))?;
+    let _ : () = write_request(payload, Some(recv_timeout_msec()))?;
+  }
+
+  return Ok(imported as i64);
+}
+
+//  ================================================================
+//
+//    Cursors
+//
+//  ================================================================
+
+//  Keyset pagination. Every row carries a cursor, and passing the
+//  cursor of the last row read continues the result after that row.
+//
+//  Scores are ordered by (score, dst), highest score first, and the
+//  cursor holds the query and the key of the last row. Scores cursor:
+//    (magic, context, src, hide_personal, kind prefix, gt, gte,
+//     last score, last dst)
+//
+//  Graph edges are ordered by (src, dst), and the cursor holds the
+//  query and the last edge. The key is applied in the backend, not by
+//  the service, see mr_graph_after. Graph cursor:
+//    (magic, context, src, focus, positive_only, last src, last dst)
+
+const SCORES_CURSOR_MAGIC : &str = "pgmer2-scores-cursor-2";
+const GRAPH_CURSOR_MAGIC  : &str = "pgmer2-graph-cursor-2";
+
+type ScoresCursor = (
+  String,
+  String,
+  String,
+  bool,
+  String,
+  Option<f64>,
+  Option<f64>,
+  f64,
+  String,
+);
+
+type GraphCursor = (String, String, String, String, bool, String, String);
+
+//  Decodes a cursor and checks that it belongs to the same kind of
+//  query and the same src. get returns the magic and src of a cursor.
+
+fn decode_cursor<T : for<'de> Deserialize<'de>>(
+  cursor : &[u8],
+  what   : &str,
+  magic  : &str,
+  src    : &str,
+  get    : impl Fn(&T) -> (&str, &str),
+) -> Result<T, MrError> {
+  let invalid = || MrError::InvalidArgument(format!("not a {} cursor", what));
+
+  let x : T = rmp_serde::from_slice(cursor).map_err(|_| invalid())?;
+  let (cursor_magic, cursor_src) = get(&x);
+
+  if cursor_magic != magic {
+    return Err(invalid());
+  }
+  if cursor_src != src {
+    return Err(MrError::InvalidArgument(format!(
+      "cursor was made for src '{}', not '{}'",
+      cursor_src, src
+    )));
+  }
+
+  return Ok(x);
+}
+
+//  Up to count scores after key, in (score, dst) order. The service
+//  returns scores highest first, but ties in any order, so a page is
+//  complete only when it holds every score tied with its lowest one.
+//  Pages are fetched from the key's score down, doubling in size
+//  until count rows above the lowest score of the page are found.
+
+fn scores_after_key(
+  context       : &str,
+  ego           : &str,
+  hide_personal : bool,
+  prefix        : &str,
+  lt            : Option<f64>,
+  lte           : Option<f64>,
+  gt            : Option<f64>,
+  gte           : Option<f64>,
+  key           : Option<(f64, &str)>,
+  count         : usize,
+) -> Result<Vec<(String, String, f64)>, MrError> {
+  if count == 0 {
+    return Ok(vec![]);
+  }
+
+  let (lt, lte) = match key {
+    Some((score, _)) => (None, Some(score)),
+    None             => (lt, lte),
+  };
+
+  let after = |x : &(String, String, f64)| match key {
+    Some((score, dst)) => x.2 < score || (x.2 == score && x.1.as_str() > dst),
+    None               => true,
+  };
+
+  let mut n = count.saturating_add(1).min(i32::MAX as usize);
+
+  loop {
+    let payload = scores_payload(
+      Some(context),
+      Some(ego),
+      Some(hide_personal),
+      mr_node_kind::of(prefix),
+      lt, lte,
+      gt, gte,
+      Some(0),
+      Some(n as i32),
+      None,
+      None,
+      None
+    )?;
+
+    let response : Vec<(String, String, f64)> =
+      request(Route::Replica, payload, Some(recv_timeout_msec()))?;
+
+    //  Scores tied with the lowest one may continue on the next page.
+    let complete = response.len() < n || n == i32::MAX as usize;
+    let lowest   = match (complete, response.last()) {
+      (false, Some(x)) => x.2,
+      _                => f64::NEG_INFINITY,
+    };
+
+    let mut rows : Vec<(String, String, f64)> =
+      response
+        .into_iter()
+        .filter(|x| after(x) && (complete || x.2 > lowest))
+        .collect();
+
+    if complete || rows.len() >= count {
+      rows.sort_by(|a, b| b.2.total_cmp(&a.2).then_with(|| a.1.cmp(&b.1)));
+      rows.truncate(count);
+      return Ok(rows);
+    }
+
+    n = n.saturating_mul(2).min(i32::MAX as usize);
+  }
+}
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_scores_after(
+  src           : Option<&str>,
+  cursor        : default!(Option<Vec<u8>>, "null"),
+  count         : default!(Option<i32>,  "16"),
+  hide_personal : default!(Option<bool>, "false"),
+  context       : default!(Option<&str>, "''"),
+  kind          : default!(Option<mr_node_kind>, "null"),
+  lt            : default!(Option<f64>,  "null"),
+  lte           : default!(Option<f64>,  "null"),
+  gt            : default!(Option<f64>,  "null"),
+  gte           : default!(Option<f64>,  "null")
+) -> Result<
+  TableIterator<'static, (
+    name!(src,    String),
+    name!(dst,    String),
+    name!(score,  f64),
+    name!(cursor, Vec<u8>),
+  )>,
+  ErrorReport,
+> {
+  let ego   = required(src, "src")?;
+  let count = count.unwrap_or(16).max(0) as usize;
+
+  //  With a cursor, the query is taken from it and the filter
+  //  arguments are ignored.
+  let (context, hide_personal, prefix, gt, gte, key) = match cursor {
+    None => (
+      context.unwrap_or("").to_string(),
+      hide_personal.unwrap_or(false),
+      kind.map(|x| x.prefix()).unwrap_or("").to_string(),
+      gt, gte,
+      None,
+    ),
+
+    Some(cursor) => {
+      let c = decode_cursor::<ScoresCursor>(
+        &cursor, "scores", SCORES_CURSOR_MAGIC, ego, |c| (c.0.as_str(), c.2.as_str())
+      )?;
+      (c.1, c.3, c.4, c.5, c.6, Some((c.7, c.8)))
+    },
+  };
+
+  let response = scores_after_key(
+    &context,
+    ego,
+    hide_personal,
+    &prefix,
+    lt, lte,
+    gt, gte,
+    key.as_ref().map(|(score, dst)| (*score, dst.as_str())),
+    count
+  )?;
+
+  let mut rows = vec![];
+
+  for (src, dst, score) in response {
+    let next : ScoresCursor = (
+      SCORES_CURSOR_MAGIC.to_string(),
+      context.clone(),
+      src.clone(),
+      hide_personal,
+      prefix.clone(),
+      gt, gte,
+      score,
+      dst.clone(),
+    );
+
+    rows.push((src, dst, score, rmp_serde::to_vec(&next).map_err(MrError::from)?));
+  }
+
+  return Ok(TableIterator::new(rows));
+}
+
+//  The graph command has no order and can't start after a key, so
+//  every page fetches the whole graph around focus and sorts it by
+//  (src, dst). Only the rows returned are bounded by count, not the
+//  cost of a page.
+
+#[pg_extern(stable, parallel_safe)]
+fn mr_graph_after(
+  src           : Option<&str>,
+  focus         : Option<&str>,
+  cursor        : default!(Option<Vec<u8>>, "null"),
+  count         : default!(Option<i32>,  "16"),
+  context       : default!(Option<&str>, "''"),
+  positive_only : default!(Option<bool>, "false")
+) -> Result<
+  TableIterator<'static, (
+    name!(src,    String),
+    name!(dst,    String),
+    name!(score,  f64),
+    name!(cursor, Vec<u8>),
+  )>,
+  ErrorReport,
+> {
+  let ego   = required(src, "src")?;
+  let count = count.unwrap_or(16).max(0) as usize;
+
+  let (context, focus, positive_only, last) = match cursor {
+    None => (
+      context.unwrap_or("").to_string(),
+      required(focus, "focus")?.to_string(),
+      positive_only.unwrap_or(false),
+      None,
+    ),
+
+    Some(cursor) => {
+      let c = decode_cursor::<GraphCursor>(
+        &cursor, "graph", GRAPH_CURSOR_MAGIC, ego, |c| (c.0.as_str(), c.2.as_str())
+      )?;
+      (c.1, c.3, c.4, Some((c.5, c.6)))
+    },
+  };
+
+  let args = (
+    ego,
+    focus.as_str(),
+    positive_only,
+    0_u32,
+    u32::MAX
+  );
+
+  let payload = command_payload(CMD_GRAPH, &context, true, &args)?;
+
+  let mut response : Vec<(String, String, f64)> =
+    request(Route::Replica, payload, Some(recv_timeout_msec()))?;
+
+  response.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+
+  let after = |x : &(String, String, f64)| match &last {
+    Some((src, dst)) => (&x.0, &x.1) > (src, dst),
+    None             => true,
+  };
+
+  let mut rows = vec![];
+
+  for (src, dst, score) in response.into_iter().filter(after).take(count) {
+    let next : GraphCursor = (
+      GRAPH_CURSOR_MAGIC.to_string(),
+      context.clone(),
+      ego.to_string(),
+      focus.clone(),
+      positive_only,
+      src.clone(),
+      dst.clone(),
+    );
+
+    rows.push((src, dst, score, rmp_serde::to_vec(&next).map_err(MrError::from)?));
+  }
+
+  return Ok(TableIterator::new(rows));
+}
+
+//  ================================================================
+//
+//    Tests
+//
+//  ================================================================
+
+#[cfg(any(test, feature = "pg_test"))]
+#[pg_schema]
+mod tests {
+  use pgrx::prelude::*;
+  use super::testing::*;
+  use std::time::SystemTime;
+
+  fn unpack_edge(x : &PgHeapTuple<'static, pgrx::AllocatedByRust>) -> (String, String, f64) {
+    return (
+      x.get_by_name("src").unwrap().unwrap(),
+      x.get_by_name("dst").unwrap().unwrap(),
+      x.get_by_name("score").unwrap().unwrap()
+    );
+  }
+
+  fn collect_edges(i : SetOfIterator<'_, PgHeapTuple<'static, pgrx::AllocatedByRust>>) -> Vec<(String, String, f64)> {
+    i.map(|x| unpack_edge(&x)).collect()
+  }
+
+  #[pg_test]
+  fn sync_deadlock() {
+    for _ in 0..3000 {
+      let _ = crate::mr_reset().unwrap();
+      let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(2.0), None).unwrap();
+      let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(1.0), None).unwrap();
+      let _ = crate::mr_put_edge(Some("U2"), Some("U3"), Some(3.0), None).unwrap();
+      let _ = crate::mr_sync(Some(1000)).unwrap();
+    }
+  }
+
+  #[pg_test]
+  fn zerorec_graph_all() {
+    let _ = crate::mr_reset().unwrap();
+
+    put_testing_edges();
+
+    let _ = crate::mr_zerorec(Some(true), None).unwrap();
+
+    let res = crate::mr_graph(
+      Some("Uadeb43da4abb"),
+      Some("U000000000000"),
+      None,
+      Some(false),
+      None,
+      None
+    ).unwrap();
+
+    let n = res.count();
+
+    assert!(n > 25);
+    assert!(n < 120);
+  }
+
+  #[pg_test]
+  fn zerorec_job() {
+    let _ = crate::mr_reset().unwrap();
+
+    put_testing_edges();
+
+    let id = crate::mr_zerorec_start().unwrap();
+
+    assert_eq!(crate::mr_job_wait(Some(id), None).unwrap(), "done");
+
+    let status : Vec<_> = crate::mr_job_status(Some(id)).unwrap().collect();
+
+    assert_eq!(status.len(), 1);
+    assert_eq!(status[0].1, "zerorec");
+    assert_eq!(status[0].2, "done");
+    assert!(status[0].3.is_some());
+    assert!(status[0].5.is_some());
+
+    let n = crate::mr_graph(
+      Some("Uadeb43da4abb"),
+      Some("U000000000000"),
+      None,
+      Some(false),
+      None,
+      None
+    ).unwrap().count();
+
+    assert!(n > 25);
+  }
+
+  #[pg_test(error = "job -1 does not exist")]
+  fn job_status_unknown() {
+    Spi::run("SELECT * FROM mr_job_status(-1)").unwrap();
+  }
+
+  #[pg_test]
+  fn zerorec_graph_positive_only() {
+    let _ = crate::mr_reset().unwrap();
+
+    put_testing_edges();
+
+    let _ = crate::mr_zerorec(Some(true), None).unwrap();
+
+    let res = crate::mr_graph(
+      Some("Uadeb43da4abb"),
+      Some("U000000000000"),
+      None,
+      Some(true),
+      None,
+      None
+    ).unwrap();
+
+    let n = res.count();
+
+    assert!(n > 25);
+    assert!(n < 120);
+  }
+
+  #[pg_test]
+  fn zerorec_reset_perf() {
+    let _ = crate::mr_reset().unwrap();
+
+    put_testing_edges();
+    let _ = crate::mr_zerorec(Some(true), None).unwrap();
+    let _ = crate::mr_reset().unwrap();
+    put_testing_edges();
+    let _ = crate::mr_create_context(Some("X")).unwrap();
+    let _ = crate::mr_create_context(Some("Y")).unwrap();
+    let _ = crate::mr_create_context(Some("Z")).unwrap();
+    let _ = crate::mr_zerorec(Some(true), None).unwrap();
+
+    let begin    = SystemTime::now();
+    let get_time = || SystemTime::now().duration_since(begin).unwrap().as_millis();
+
+    let _ = crate::mr_graph(
+      Some("Uadeb43da4abb"),
+      Some("U000000000000"),
+      None,
+      Some(true),
+      None,
+      None
+    ).unwrap();
+
+    assert!(get_time() < 10);
+  }
+
+  #[pg_test]
+  fn zerorec_scores() {
+    let _ = crate::mr_reset().unwrap();
+
+    put_testing_edges();
+
+    let _ = crate::mr_zerorec(Some(true), None).unwrap();
+
+    let res = crate::mr_scores(
+      Some("Uadeb43da4abb"),
+      Some(true),
+      Some(""),
+      Some("B"),
+      None,
+      None,
+      Some(0.0),
+      None,
+      Some(0),
+      Some(i32::MAX),
+      None, None, None
+    ).unwrap();
+
+    let n = res.count();
+
+    assert!(n > 5);
+    assert!(n < 80);
+  }
+
+  #[pg_test]
+  fn service() {
+    let ver = crate::mr_service();
+
+    //  check if ver is in form "X.Y.Z"
+    assert_eq!(ver.split(".").map(|x|
+      x.parse::<i32>().unwrap()
+    ).count(), 3);
+  }
+
+  #[pg_test]
+  fn service_reconnect() {
+    let ver = crate::mr_service();
+
+    crate::disconnect_all();
+    assert_eq!(crate::mr_service(), ver);
+    assert_eq!(crate::mr_service(), ver);
+  }
+
+  #[pg_test]
+  fn service_url_guc() {
+    Spi::run("SET pgmer2.service_url = 'tcp://127.0.0.1:1'").unwrap();
+    assert_eq!(crate::mr_service_url(), "tcp://127.0.0.1:1");
+
+    Spi::run("RESET pgmer2.service_url").unwrap();
+    assert_ne!(crate::mr_service_url(), "tcp://127.0.0.1:1");
+  }
+
+  #[pg_test(error = "canceling statement due to user request")]
+  fn request_cancel() {
+    //  A service that never responds.
+    let server = nng::Socket::new(nng::Protocol::Rep0).unwrap();
+    server.listen("tcp://127.0.0.1:10299").unwrap();
+
+    Spi::run("SET pgmer2.service_url = 'tcp://127.0.0.1:10299'").unwrap();
+
+    unsafe {
+      pg_sys::QueryCancelPending = true;
+      pg_sys::InterruptPending   = true;
+    }
+
+    let _ = crate::mr_sync(Some(6000000));
+  }
+
+  #[pg_test(error = "canceling statement due to statement timeout")]
+  fn request_statement_timeout() {
+    //  A service that never responds.
+    let server = nng::Socket::new(nng::Protocol::Rep0).unwrap();
+    server.listen("tcp://127.0.0.1:10298").unwrap();
+
+    Spi::run("SET pgmer2.service_url = 'tcp://127.0.0.1:10298'").unwrap();
+
+    //  The test runs in one statement, so setting statement_timeout now
+    //  would not arm it. Arm the timer the way the executor does.
+    unsafe {
+      pg_sys::enable_timeout_after(pg_sys::TimeoutId_STATEMENT_TIMEOUT, 200);
+    }
+
+    let _ = crate::mr_sync(Some(6000000));
+  }
+
+  #[pg_test]
+  fn node_kind() {
+    assert_eq!(crate::node_kind(Some("U1")),  Some(crate::mr_node_kind::U));
+    assert_eq!(crate::node_kind(Some("Bx")),  Some(crate::mr_node_kind::B));
+    assert_eq!(crate::node_kind(Some("b1")),  None);
+    assert_eq!(crate::node_kind(None),        None);
+
+    let kind = Spi::get_one::<String>("SELECT mr_node_kind('C1')::text").unwrap();
+    assert_eq!(kind, Some("C".to_string()));
+  }
+
+  #[pg_test(error = "invalid input value for enum mr_node_kind: \"b\"")]
+  fn node_kind_invalid() {
+    Spi::run("SELECT mr_scores('U1', kind => 'b')").unwrap();
+  }
+
+  #[pg_test]
+  fn node_kind_empty() {
+    let _ = crate::mr_reset().unwrap();
+
+    let _ = crate::mr_put_edge(Some("U1"), Some("B1"), Some(1.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), None).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let any = Spi::get_one::<i64>("SELECT count(*) FROM mr_scores('U1', kind => '')").unwrap();
+    let all = Spi::get_one::<i64>("SELECT count(*) FROM mr_scores('U1')").unwrap();
+    assert_eq!(any, all);
+
+    let beacons = Spi::get_one::<i64>("SELECT count(*) FROM mr_scores('U1', kind => 'B')").unwrap();
+    assert_eq!(beacons, Some(1));
+  }
+
+  #[pg_test(error = "contexts can't be listed by this service, pass them explicitly")]
+  fn export_all_contexts() {
+    Spi::run("SELECT mr_export()").unwrap();
+  }
+
+  #[pg_test]
+  fn error_sqlstate() {
+    Spi::run("
+      CREATE FUNCTION sqlstate_of(q text) RETURNS text AS $$
+      BEGIN
+        EXECUTE q;
+        RETURN '00000';
+      EXCEPTION WHEN OTHERS THEN
+        RETURN SQLSTATE;
+      END
+      $$ LANGUAGE plpgsql
+    ").unwrap();
+
+    let state = |q : &str| -> String {
+      Spi::get_one_with_args::<String>(
+        "SELECT sqlstate_of($1)",
+        vec![(PgBuiltInOids::TEXTOID.oid(), q.into_datum())],
+      ).unwrap().unwrap()
+    };
+
+    assert_eq!(state("SELECT mr_node_score(NULL, 'U1')"),            "22004");
+    assert_eq!(state("SELECT mr_scores('U1', lt => 1, lte => 1)"),   "22023");
+
+    Spi::run("SET pgmer2.service_url = 'tcp://127.0.0.1:1'").unwrap();
+    assert_eq!(state("SELECT mr_put_edge('U1', 'U2', 1.0)"),         "08006");
+    Spi::run("RESET pgmer2.service_url").unwrap();
+  }
+
+  #[pg_test]
+  fn service_endpoints() {
+    Spi::run("SET pgmer2.service_url = 'tcp://127.0.0.1:1, tcp://127.0.0.1:2'").unwrap();
+    Spi::run("SET pgmer2.replica_urls = 'tcp://127.0.0.1:3'").unwrap();
+
+    let res : Vec<(String, String)> = crate::mr_service_endpoints().collect();
+
+    assert_eq!(res, vec![
+      ("tcp://127.0.0.1:1".to_string(), "primary".to_string()),
+      ("tcp://127.0.0.1:2".to_string(), "primary".to_string()),
+      ("tcp://127.0.0.1:3".to_string(), "replica".to_string()),
+    ]);
+
+    Spi::run("RESET pgmer2.service_url").unwrap();
+    Spi::run("RESET pgmer2.replica_urls").unwrap();
+  }
+
+  #[pg_test]
+  fn replica_failover() {
+    Spi::run("SET pgmer2.replica_urls = 'tcp://127.0.0.1:1'").unwrap();
+
+    let _ = crate::mr_reset().unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), None).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    //  Replica is unreachable, so the read falls back to the primary.
+    assert_eq!(crate::mr_edgelist(None).unwrap().count(), 1);
+
+    Spi::run("RESET pgmer2.replica_urls").unwrap();
+  }
+
+  #[pg_test]
+  fn transactional_pending() {
+    let _ = crate::mr_reset().unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    Spi::run("SET pgmer2.transactional = on").unwrap();
+
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), None).unwrap();
+    let _ = crate::mr_delete_edge(Some("U1"), Some("U2"), None).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    //  Nothing is sent until commit.
+    assert_eq!(crate::pending_writes(), 2);
+    assert_eq!(crate::mr_edgelist(None).unwrap().count(), 0);
+
+    Spi::run("RESET pgmer2.transactional").unwrap();
+  }
+
+  #[pg_test]
+  fn transactional_subxact_rollback() {
+    Spi::run("SET pgmer2.transactional = on").unwrap();
+
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), None).unwrap();
+
+    unsafe { pg_sys::BeginInternalSubTransaction(std::ptr::null()); }
+    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(1.0), None).unwrap();
+    assert_eq!(crate::pending_writes(), 2);
+    unsafe { pg_sys::RollbackAndReleaseCurrentSubTransaction(); }
+
+    assert_eq!(crate::pending_writes(), 1);
+
+    unsafe { pg_sys::BeginInternalSubTransaction(std::ptr::null()); }
+    let _ = crate::mr_put_edge(Some("U1"), Some("U4"), Some(1.0), None).unwrap();
+    unsafe { pg_sys::ReleaseCurrentSubTransaction(); }
+
+    assert_eq!(crate::pending_writes(), 2);
+
+    Spi::run("RESET pgmer2.transactional").unwrap();
+  }
+
+  #[pg_test]
+  fn edge_uncontexted() {
+    let _ = crate::mr_reset().unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let res = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), None).unwrap();
+
+    let n = res.map(|x| {
+      let (ego, target, score) = unpack_edge(&x);
+      assert_eq!(ego,    "U1");
+      assert_eq!(target, "U2");
+      assert_eq!(score,  1.0);
+    }).count();
+
+    assert_eq!(n, 1);
+  }
+
+  #[pg_test]
+  fn edge_contexted() {
+    let _ = crate::mr_reset().unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let res = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), Some("X")).unwrap();
+
+    let n = res.map(|x| {
+      let (ego, target, score) = unpack_edge(&x);
+      assert_eq!(ego,    "U1");
+      assert_eq!(target, "U2");
+      assert_eq!(score,   1.0);
+    }).count();
+
+    assert_eq!(n, 1);
+  }
+
+  #[pg_test]
+  fn create_context() {
+    let _ = crate::mr_reset().unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), None).unwrap();
+    let _ = crate::mr_create_context(Some("X"));
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let res = crate::mr_edgelist(Some("X")).unwrap();
+
+    let n = res.map(|x| {
+      let (ego, target, score) = unpack_edge(&x);
+      assert_eq!(ego,    "U1");
+      assert_eq!(target, "U2");
+      assert!(score > 0.99);
+      assert!(score < 1.01);
+    }).count();
+
+    assert_eq!(n, 1);
+  }
+
+  #[pg_test]
+  fn null_context_is_sum() {
+    let _ = crate::mr_reset().unwrap();
+
+    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(1.0), Some("X")).unwrap();
+    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(2.0), Some("Y")).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let res = crate::mr_edgelist(None).unwrap();
+
+    let n = res.map(|x| {
+      let (ego, target, score) = unpack_edge(&x);
+      assert_eq!(ego,    "B1");
+      assert_eq!(target, "B2");
+      assert!(score > 2.99);
+      assert!(score < 3.01);
+    }).count();
+
+    assert_eq!(n, 1);
+  }
+
+  #[pg_test]
+  fn put_edges() {
+    let _ = crate::mr_reset().unwrap();
+
+    let n = crate::mr_put_edges(
+      Some(vec![Some("U1".into()), Some("U1".into()), Some("U2".into()), Some("U1".into())]),
+      Some(vec![Some("U2".into()), Some("U3".into()), Some("U3".into()), Some("U2".into())]),
+      Some(vec![Some(1.0),         Some(2.0),         Some(3.0),         Some(4.0)]),
+      Some("X")
+    ).unwrap();
+
+    //  Duplicate edge is sent once, with the last weight.
+    assert_eq!(n, 3);
+
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let mut res = collect_edges(crate::mr_edgelist(Some("X")).unwrap());
+    res.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+
+    assert_eq!(res, vec![
+      ("U1".to_string(), "U2".to_string(), 4.0),
+      ("U1".to_string(), "U3".to_string(), 2.0),
+      ("U2".to_string(), "U3".to_string(), 3.0),
+    ]);
+  }
+
+  #[pg_test]
+  fn delete_contexted_edge() {
+    let _ = crate::mr_reset().unwrap();
+
+    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(1.0), Some("X")).unwrap();
+    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(2.0), Some("Y")).unwrap();
+    let _ = crate::mr_delete_edge(Some("B1"), Some("B2"), Some("X")).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    //  We should still have "Y" edge.
+    let res = crate::mr_edgelist(None).unwrap();
+
+    let n = res.map(|x| {
+      let (ego, target, score) = unpack_edge(&x);
+      assert_eq!(ego,    "B1");
+      assert_eq!(target, "B2");
+      assert_eq!(score,  2.0);
+    }).count();
+
+    assert_eq!(n, 1);
+  }
+
+  #[pg_test]
+  fn null_context_invariant() {
+    let _ = crate::mr_reset().unwrap();
+
+    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(1.0), Some("X")).unwrap();
+    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(2.0), Some("Y")).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    //  Delete and put back again.
+    let _ = crate::mr_delete_edge(Some("B1"), Some("B2"), Some("X"));
+    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(1.0), Some("X"));
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let res = crate::mr_edgelist(None).unwrap();
+
+    let n = res.map(|x| {
+      let (ego, target, score) = unpack_edge(&x);
+      assert_eq!(ego,    "B1");
+      assert_eq!(target, "B2");
+      assert_eq!(score,  3.0);
+    }).count();
+
+    assert_eq!(n, 1);
+  }
+
+  #[pg_test]
+  fn attach_table() {
+    let _ = crate::mr_reset().unwrap();
+
+    Spi::run("CREATE TABLE votes (src text, dst text, weight float8)").unwrap();
+    Spi::run("INSERT INTO votes VALUES ('U1', 'U2', 1.0), ('U1', 'U3', 2.0)").unwrap();
+
+    let n = crate::mr_attach_table(
+      Some("votes"),
+      Some("src"), Some("dst"), Some("weight"),
+      None, Some("X"),
+      Some(true)
+    ).unwrap();
+
+    assert_eq!(n, 2);
+
+    //  Nothing is sent until commit, which a test can't do, so the
+    //  pending writes are flushed by hand.
+    assert_eq!(crate::pending_writes(), 2);
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+    assert_eq!(crate::mr_edgelist(Some("X")).unwrap().count(), 0);
+
+    crate::flush_pending_writes().unwrap();
+
+    Spi::run("INSERT INTO votes VALUES ('U2', 'U3', 3.0)").unwrap();
+    Spi::run("UPDATE votes SET weight = 5.0 WHERE dst = 'U2'").unwrap();
+    Spi::run("DELETE FROM votes WHERE dst = 'U3' AND src = 'U1'").unwrap();
+
+    //  Writes of a rolled back row change are dropped.
+    unsafe { pg_sys::BeginInternalSubTransaction(std::ptr::null()); }
+    Spi::run("INSERT INTO votes VALUES ('U3', 'U2', 1.0)").unwrap();
+    unsafe { pg_sys::RollbackAndReleaseCurrentSubTransaction(); }
+
+    assert_eq!(crate::pending_writes(), 3);
+
+    crate::flush_pending_writes().unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let mut res = collect_edges(crate::mr_edgelist(Some("X")).unwrap());
+    res.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+
+    assert_eq!(res, vec![
+      ("U1".to_string(), "U2".to_string(), 5.0),
+      ("U2".to_string(), "U3".to_string(), 3.0),
+    ]);
+
+    let _ = crate::mr_detach_table(Some("votes")).unwrap();
+    Spi::run("INSERT INTO votes VALUES ('U3', 'U1', 1.0)").unwrap();
+
+    assert_eq!(crate::pending_writes(), 0);
+  }
+
+  #[pg_test]
+  fn diff_reconcile() {
+    let _ = crate::mr_reset().unwrap();
+
+    Spi::run("CREATE TABLE votes (src text, dst text, weight float8)").unwrap();
+    Spi::run("INSERT INTO votes VALUES ('U1', 'U2', 1.0), ('U1', 'U3', 2.0)").unwrap();
+
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(5.0), Some("X")).unwrap();
+    let _ = crate::mr_put_edge(Some("U2"), Some("U3"), Some(1.0), Some("X")).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let diff : Vec<(String, String, String)> =
+      crate::mr_diff(Some("votes"), Some("X"), Some("src"), Some("dst"), Some("weight"), None)
+        .unwrap()
+        .map(|x| (x.0, x.1, x.2))
+        .collect();
+
+    assert_eq!(diff, vec![
+      ("U1".to_string(), "U2".to_string(), "weight_mismatch".to_string()),
+      ("U1".to_string(), "U3".to_string(), "missing".to_string()),
+      ("U2".to_string(), "U3".to_string(), "extra".to_string()),
+    ]);
+
+    let summary : Vec<_> =
+      crate::mr_reconcile(Some("votes"), Some("X"), Some(false), Some("src"), Some("dst"), Some("weight"), None)
+        .unwrap()
+        .collect();
+
+    assert_eq!(summary, vec![(1, 1, 1, false)]);
+
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    assert_eq!(
+      crate::mr_diff(Some("votes"), Some("X"), Some("src"), Some("dst"), Some("weight"), None)
+        .unwrap()
+        .count(),
+      0
+    );
+  }
+
+  #[pg_test]
+  fn export_import() {
+    let _ = crate::mr_reset().unwrap();
+
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), Some("X")).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(2.0), Some("X")).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let snapshot = crate::mr_export(Some(vec!["X".to_string()])).unwrap();
+
+    let _ = crate::mr_reset().unwrap();
+    let _ = crate::mr_put_edge(Some("U2"), Some("U3"), Some(3.0), Some("X")).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    assert_eq!(crate::mr_import(Some(snapshot.clone()), Some("merge")).unwrap(), 2);
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+    assert_eq!(crate::mr_edgelist(Some("X")).unwrap().count(), 3);
+
+    assert_eq!(crate::mr_import(Some(snapshot), Some("replace")).unwrap(), 2);
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let mut res = collect_edges(crate::mr_edgelist(Some("X")).unwrap());
+    res.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+
+    assert_eq!(res, vec![
+      ("U1".to_string(), "U2".to_string(), 1.0),
+      ("U1".to_string(), "U3".to_string(), 2.0),
+    ]);
+  }
+
+  #[pg_test]
+  fn export_import_default_context() {
+    let _ = crate::mr_reset().unwrap();
+
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), Some("X")).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let snapshot = crate::mr_export(Some(vec!["".to_string(), "X".to_string()])).unwrap();
+
+    let _ = crate::mr_reset().unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    assert_eq!(crate::mr_import(Some(snapshot.clone()), Some("merge")).unwrap(), 1);
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    assert_eq!(crate::mr_import(Some(snapshot), Some("replace")).unwrap(), 1);
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let expected = vec![("U1".to_string(), "U2".to_string(), 1.0)];
+
+    assert_eq!(collect_edges(crate::mr_edgelist(Some("X")).unwrap()), expected);
+    assert_eq!(collect_edges(crate::mr_edgelist(None).unwrap()),      expected);
+  }
+
+  #[pg_test]
+  fn outbox_drain() {
+    let _ = crate::mr_reset().unwrap();
+
+    Spi::run("SET pgmer2.outbox = on").unwrap();
+
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), Some("X")).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(2.0), Some("X")).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let queued = Spi::get_one::<i64>("SELECT queued FROM mr_outbox_status").unwrap();
+    assert_eq!(queued, Some(2));
+    assert_eq!(crate::mr_edgelist(Some("X")).unwrap().count(), 0);
+
+    //  Rows are sent only after commit, which a test can't do.
+    assert_eq!(crate::outbox::drain().unwrap(), 0);
+    crate::outbox::pre_commit().unwrap();
+
+    assert_eq!(crate::outbox::drain().unwrap(), 2);
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let queued = Spi::get_one::<i64>("SELECT queued FROM mr_outbox_status").unwrap();
+    assert_eq!(queued, Some(0));
+    assert_eq!(crate::mr_edgelist(Some("X")).unwrap().count(), 2);
+
+    Spi::run("RESET pgmer2.outbox").unwrap();
   }
 
   #[pg_test]
-  fn sync_deadlock() {
-    for _ in 0..3000 {
-      let _ = crate::mr_reset().unwrap();
-      let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(2.0), None).unwrap();
-      let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(1.0), None).unwrap();
-      let _ = crate::mr_put_edge(Some("U2"), Some("U3"), Some(3.0), None).unwrap();
-      let _ = crate::mr_sync(Some(1000)).unwrap();
-    }
+  fn fdw_edges() {
+    let _ = crate::mr_reset().unwrap();
+
+    Spi::run("INSERT INTO mr_edges VALUES ('U1', 'U2', 1.0, 'X'), ('U1', 'U3', 2.0, 'X')").unwrap();
+    Spi::run("UPDATE mr_edges SET weight = 4.0 WHERE context = 'X' AND dst = 'U2'").unwrap();
+    Spi::run("DELETE FROM mr_edges WHERE context = 'X' AND dst = 'U3'").unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let n = Spi::get_one::<i64>("SELECT count(*) FROM mr_edges WHERE context = 'X'").unwrap();
+    assert_eq!(n, Some(1));
+
+    let w = Spi::get_one::<f64>("SELECT weight FROM mr_edges WHERE context = 'X' AND src = 'U1'").unwrap();
+    assert_eq!(w, Some(4.0));
+
+    let n = Spi::get_one::<i64>("SELECT count(*) FROM mr_nodes WHERE context = 'X'").unwrap();
+    assert_eq!(n, Some(2));
   }
 
   #[pg_test]
-  fn zerorec_graph_all() {
+  fn fdw_scores() {
     let _ = crate::mr_reset().unwrap();
 
-    put_testing_edges();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(2.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(1.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U2"), Some("U3"), Some(3.0), None).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
 
-    let _ = crate::mr_zerorec(Some(true), None).unwrap();
+    let n = Spi::get_one::<i64>(
+      "SELECT count(*) FROM (SELECT * FROM mr_scores WHERE src = 'U1' LIMIT 2) s"
+    ).unwrap();
+    assert_eq!(n, Some(2));
 
-    let res = crate::mr_graph(
-      Some("Uadeb43da4abb"),
-      Some("U000000000000"),
-      None,
-      Some(false),
-      None,
-      None
+    let n = Spi::get_one::<i64>(
+      "SELECT count(*) FROM mr_scores WHERE src = 'U1' AND dst = 'U2' AND score > 0"
     ).unwrap();
+    assert_eq!(n, Some(1));
+  }
 
-    let n = res.count();
+  #[pg_test]
+  fn ranked_scores() {
+    let _ = crate::mr_reset().unwrap();
 
-    assert!(n > 25);
-    assert!(n < 120);
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(2.0), Some("X")).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("B1"), Some(1.0), Some("X")).unwrap();
+    let _ = crate::mr_put_edge(Some("U2"), Some("U1"), Some(3.0), Some("X")).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let rows = Spi::connect(|client| {
+      client.select(
+        "SELECT rank, dst, dst_kind::text, context FROM mr_ranked_scores('U1', context => 'X', index => 1)",
+        None,
+        None,
+      ).unwrap().map(|row| (
+        row.get::<i64>(1).unwrap().unwrap(),
+        row.get::<String>(2).unwrap().unwrap(),
+        row.get::<String>(3).unwrap().unwrap(),
+        row.get::<String>(4).unwrap().unwrap(),
+      )).collect::<Vec<_>>()
+    });
+
+    assert_eq!(rows.len(), 2);
+    for (n, (rank, dst, kind, context)) in rows.iter().enumerate() {
+      assert_eq!(*rank, n as i64 + 2);
+      assert_eq!(kind, &dst[..1]);
+      assert_eq!(context, "X");
+    }
   }
 
   #[pg_test]
-  fn zerorec_graph_positive_only() {
+  fn scores_paged() {
     let _ = crate::mr_reset().unwrap();
 
-    put_testing_edges();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(2.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(1.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U4"), Some(3.0), None).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
 
-    let _ = crate::mr_zerorec(Some(true), None).unwrap();
+    let query = "SELECT string_agg(dst, ',') FROM mr_scores('U1', count => 3)";
 
-    let res = crate::mr_graph(
-      Some("Uadeb43da4abb"),
-      Some("U000000000000"),
-      None,
-      Some(true),
-      None,
-      None
-    ).unwrap();
+    let whole = Spi::get_one::<String>(query).unwrap();
 
-    let n = res.count();
+    Spi::run("SET pgmer2.page_size = 1").unwrap();
+    let paged = Spi::get_one::<String>(query).unwrap();
+    Spi::run("RESET pgmer2.page_size").unwrap();
 
-    assert!(n > 25);
-    assert!(n < 120);
+    assert_eq!(paged, whole);
+    assert_eq!(paged.unwrap().split(',').count(), 3);
   }
 
   #[pg_test]
-  fn zerorec_reset_perf() {
+  fn scores_cursor() {
     let _ = crate::mr_reset().unwrap();
 
-    put_testing_edges();
-    let _ = crate::mr_zerorec(Some(true), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(2.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(1.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U4"), Some(1.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U5"), Some(3.0), None).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+
+    let all = Spi::get_one::<String>(
+      "SELECT string_agg(dst, ',' ORDER BY dst) FROM mr_scores('U1', count => 100)"
+    ).unwrap();
+
+    let paged = Spi::get_one::<String>("
+      WITH first AS (
+        SELECT dst, cursor, row_number() OVER () AS n FROM mr_scores_after('U1', count => 2)
+      ),
+      rest AS (
+        SELECT dst FROM mr_scores_after('U1',
+          (SELECT cursor FROM first ORDER BY n DESC LIMIT 1), count => 100)
+      )
+      SELECT string_agg(dst, ',' ORDER BY dst) FROM (
+        SELECT dst FROM first UNION ALL SELECT dst FROM rest
+      ) x
+    ").unwrap();
+
+    assert_eq!(paged, all);
+  }
+
+  #[pg_test]
+  fn scores_cursor_ties() {
     let _ = crate::mr_reset().unwrap();
-    put_testing_edges();
-    let _ = crate::mr_create_context(Some("X")).unwrap();
-    let _ = crate::mr_create_context(Some("Y")).unwrap();
-    let _ = crate::mr_create_context(Some("Z")).unwrap();
-    let _ = crate::mr_zerorec(Some(true), None).unwrap();
 
-    let begin    = SystemTime::now();
-    let get_time = || SystemTime::now().duration_since(begin).unwrap().as_millis();
+    for dst in ["U2", "U3", "U4", "U5", "U6"] {
+      let _ = crate::mr_put_edge(Some("U1"), Some(dst), Some(1.0), None).unwrap();
+    }
+    let _ = crate::mr_sync(Some(1000)).unwrap();
 
-    let _ = crate::mr_graph(
-      Some("Uadeb43da4abb"),
-      Some("U000000000000"),
-      None,
-      Some(true),
-      None,
-      None
-    ).unwrap();
+    let mut cursor = None;
+    let mut seen   = vec![];
 
-    assert!(get_time() < 10);
+    //  One row per page, so every page starts inside a tie.
+    while seen.len() < 100 {
+      let rows : Vec<_> = crate::mr_scores_after(
+        Some("U1"), cursor.clone(), Some(1), None, None, None, None, None, None, None
+      ).unwrap().collect();
+
+      match rows.into_iter().next() {
+        Some((_, dst, _, next)) => {
+          assert!(next.len() < 128);
+          seen.push(dst);
+          cursor = Some(next);
+        },
+        None => break,
+      }
+    }
+
+    let mut unique = seen.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), seen.len());
+
+    let all = Spi::get_one::<i64>("SELECT count(*) FROM mr_scores('U1', count => 100)").unwrap();
+    assert_eq!(all, Some(seen.len() as i64));
   }
 
   #[pg_test]
-  fn zerorec_scores() {
+  fn graph_cursor() {
     let _ = crate::mr_reset().unwrap();
 
     put_testing_edges();
 
     let _ = crate::mr_zerorec(Some(true), None).unwrap();
 
-    let res = crate::mr_scores(
+    let all = crate::mr_graph(
       Some("Uadeb43da4abb"),
-      Some(true),
-      Some(""),
-      Some("B"),
-      None,
-      None,
-      Some(0.0),
+      Some("U000000000000"),
       None,
+      Some(false),
       Some(0),
-      Some(i32::MAX)
-    ).unwrap();
+      Some(1000)
+    ).unwrap().count();
 
-    let n = res.count();
+    let mut cursor = None;
+    let mut seen   = vec![];
 
-    assert!(n > 5);
-    assert!(n < 80);
-  }
+    while seen.len() <= all {
+      let rows : Vec<_> = crate::mr_graph_after(
+        Some("Uadeb43da4abb"), Some("U000000000000"), cursor.clone(), Some(7), None, None
+      ).unwrap().collect();
 
-  #[pg_test]
-  fn service() {
-    let ver = crate::mr_service();
+      match rows.last() {
+        Some(x) => cursor = Some(x.3.clone()),
+        None    => break,
+      }
+      seen.extend(rows.into_iter().map(|(src, dst, _, _)| (src, dst)));
+    }
 
-    //  check if ver is in form "X.Y.Z"
-    assert_eq!(ver.split(".").map(|x|
-      x.parse::<i32>().unwrap()
-    ).count(), 3);
+    let mut unique = seen.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), seen.len());
+    assert_eq!(seen.len(), all);
   }
 
   #[pg_test]
-  fn edge_uncontexted() {
+  fn scores_hide_negative() {
     let _ = crate::mr_reset().unwrap();
-    let _ = crate::mr_sync(Some(1000)).unwrap();
 
-    let res = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(2.0),  None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(-1.0), None).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
 
-    let n = res.map(|x| {
-      let (ego, target, score) = unpack_edge(&x);
-      assert_eq!(ego,    "U1");
-      assert_eq!(target, "U2");
-      assert_eq!(score,  1.0);
-    }).count();
+    let negative = Spi::get_one::<i64>(
+      "SELECT count(*) FROM mr_scores('U1', count => 100) WHERE score < 0"
+    ).unwrap();
+    assert_eq!(negative, Some(1));
 
-    assert_eq!(n, 1);
+    let negative = Spi::get_one::<i64>(
+      "SELECT count(*) FROM mr_scores('U1', count => 100, hide_negative => true) WHERE score < 0"
+    ).unwrap();
+    assert_eq!(negative, Some(0));
   }
 
   #[pg_test]
-  fn edge_contexted() {
+  fn scores_order() {
     let _ = crate::mr_reset().unwrap();
+
+    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(2.0),  None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0),  None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U4"), Some(-1.0), None).unwrap();
     let _ = crate::mr_sync(Some(1000)).unwrap();
 
-    let res = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), Some("X")).unwrap();
+    let dsts = |query : &str| -> Vec<String> {
+      Spi::connect(|client| {
+        client
+          .select(query, None, None)
+          .unwrap()
+          .map(|row| row.get::<String>(1).unwrap().unwrap())
+          .collect()
+      })
+    };
 
-    let n = res.map(|x| {
-      let (ego, target, score) = unpack_edge(&x);
-      assert_eq!(ego,    "U1");
-      assert_eq!(target, "U2");
-      assert_eq!(score,   1.0);
-    }).count();
+    let by_name = dsts("SELECT dst FROM mr_scores('U1', count => 100, order_by => 'name')");
+    let mut sorted = by_name.clone();
+    sorted.sort();
+    assert_eq!(by_name, sorted);
 
-    assert_eq!(n, 1);
+    let ascending = dsts("SELECT dst FROM mr_scores('U1', count => 100, order_by => 'score_asc')");
+    assert_eq!(ascending.first().map(|x| x.as_str()), Some("U4"));
+
+    //  Index and count apply after ordering.
+    let second = dsts("SELECT dst FROM mr_scores('U1', index => 1, count => 1, order_by => 'name')");
+    assert_eq!(second, by_name[1..2].to_vec());
+
+    let small = Spi::get_one::<i64>(
+      "SELECT count(*) FROM mr_scores('U1', count => 100, min_abs_score => 0.01) WHERE abs(score) < 0.01"
+    ).unwrap();
+    assert_eq!(small, Some(0));
   }
 
   #[pg_test]
-  fn create_context() {
+  fn node_score_context() {
     let _ = crate::mr_reset().unwrap();
-    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(1.0), None).unwrap();
-    let _ = crate::mr_create_context(Some("X"));
+
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(2.0), Some("X")).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(1.0), Some("X")).unwrap();
+    let _ = crate::mr_put_edge(Some("U3"), Some("U2"), Some(3.0), Some("X")).unwrap();
     let _ = crate::mr_sync(Some(1000)).unwrap();
 
-    let res = crate::mr_edgelist(Some("X")).unwrap();
+    let res = crate::mr_node_score(Some("U1"), Some("U2"), Some("X")).unwrap();
 
     let n = res.map(|x| {
       let (ego, target, score) = unpack_edge(&x);
-      assert_eq!(ego,    "U1");
+      assert_eq!(ego, "U1");
       assert_eq!(target, "U2");
-      assert!(score > 0.99);
-      assert!(score < 1.01);
+      assert!(score > 0.3);
+      assert!(score < 0.45);
     }).count();
 
     assert_eq!(n, 1);
   }
 
   #[pg_test]
-  fn null_context_is_sum() {
+  fn node_scores() {
     let _ = crate::mr_reset().unwrap();
 
-    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(1.0), Some("X")).unwrap();
-    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(2.0), Some("Y")).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(2.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(1.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U2"), Some("U3"), Some(3.0), None).unwrap();
     let _ = crate::mr_sync(Some(1000)).unwrap();
 
-    let res = crate::mr_edgelist(None).unwrap();
+    let dsts = vec!["U2".to_string(), "U3".to_string()];
 
-    let n = res.map(|x| {
-      let (ego, target, score) = unpack_edge(&x);
-      assert_eq!(ego,    "B1");
-      assert_eq!(target, "B2");
-      assert!(score > 2.99);
-      assert!(score < 3.01);
+    let n = crate::mr_node_scores_for_src(Some("U1"), Some(dsts.clone()), None).unwrap().map(|x| {
+      let (ego, _, score) = unpack_edge(&x);
+      assert_eq!(ego, "U1");
+      assert!(score > 0.0);
     }).count();
+    assert_eq!(n, 2);
 
-    assert_eq!(n, 1);
+    let srcs = vec!["U1".to_string(), "U2".to_string()];
+
+    let n = crate::mr_node_scores_for_srcs(Some(srcs), Some(dsts), None).unwrap().count();
+    assert_eq!(n, 4);
+
+    let n = Spi::get_one::<i64>(
+      "SELECT count(*) FROM unnest(ARRAY['U1', 'U2']) AS u(id),
+         LATERAL mr_node_scores(u.id, ARRAY['U3']) s"
+    ).unwrap();
+    assert_eq!(n, Some(2));
   }
 
   #[pg_test]
-  fn delete_contexted_edge() {
+  fn scores_bulk() {
     let _ = crate::mr_reset().unwrap();
 
-    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(1.0), Some("X")).unwrap();
-    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(2.0), Some("Y")).unwrap();
-    let _ = crate::mr_delete_edge(Some("B1"), Some("B2"), Some("X")).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(2.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(1.0), None).unwrap();
+    let _ = crate::mr_put_edge(Some("U2"), Some("U3"), Some(3.0), None).unwrap();
     let _ = crate::mr_sync(Some(1000)).unwrap();
 
-    //  We should still have "Y" edge.
-    let res = crate::mr_edgelist(None).unwrap();
+    Spi::run("SET pgmer2.parallelism = 1").unwrap();
 
-    let n = res.map(|x| {
-      let (ego, target, score) = unpack_edge(&x);
-      assert_eq!(ego,    "B1");
-      assert_eq!(target, "B2");
-      assert_eq!(score,  2.0);
-    }).count();
+    let bulk = Spi::get_one::<String>(
+      "SELECT string_agg(src || dst, ',' ORDER BY src, dst) FROM mr_scores_bulk(ARRAY['U1', 'U2'])"
+    ).unwrap();
 
-    assert_eq!(n, 1);
+    Spi::run("RESET pgmer2.parallelism").unwrap();
+
+    let single = Spi::get_one::<String>(
+      "SELECT string_agg(src || dst, ',' ORDER BY src, dst) FROM (
+         SELECT * FROM mr_scores('U1') UNION ALL SELECT * FROM mr_scores('U2')
+       ) s"
+    ).unwrap();
+
+    assert_eq!(bulk, single);
   }
 
   #[pg_test]
-  fn null_context_invariant() {
+  fn parallel_safe() {
+    let unsafe_reads = Spi::get_one::<i64>(
+      "SELECT count(*) FROM pg_proc
+        WHERE proname IN ('mr_scores', 'mr_node_score', 'mr_node_scores', 'mr_graph')
+          AND proparallel <> 's'"
+    ).unwrap();
+    assert_eq!(unsafe_reads, Some(0));
+
+    let safe_writes = Spi::get_one::<i64>(
+      "SELECT count(*) FROM pg_proc
+        WHERE proname IN ('mr_put_edge', 'mr_delete_edge', 'mr_reset')
+          AND proparallel = 's'"
+    ).unwrap();
+    assert_eq!(safe_writes, Some(0));
+  }
+
+  #[pg_test]
+  fn volatility() {
+    let volatility = |name : &str| Spi::get_one::<String>(&format!(
+      "SELECT provolatile::text FROM pg_proc WHERE proname = '{}' LIMIT 1", name
+    )).unwrap().unwrap();
+
+    assert_eq!(volatility("mr_node_kind"), "i");
+    assert_eq!(volatility("mr_scores"),    "s");
+    assert_eq!(volatility("mr_graph"),     "s");
+    assert_eq!(volatility("mr_edgelist"),  "s");
+    assert_eq!(volatility("mr_sync"),      "v");
+    assert_eq!(volatility("mr_put_edge"),  "v");
+  }
+
+  #[pg_test]
+  fn response_cache() {
     let _ = crate::mr_reset().unwrap();
 
-    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(1.0), Some("X")).unwrap();
-    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(2.0), Some("Y")).unwrap();
+    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(2.0), None).unwrap();
     let _ = crate::mr_sync(Some(1000)).unwrap();
 
-    //  Delete and put back again.
-    let _ = crate::mr_delete_edge(Some("B1"), Some("B2"), Some("X"));
-    let _ = crate::mr_put_edge(Some("B1"), Some("B2"), Some(1.0), Some("X"));
-    let _ = crate::mr_sync(Some(1000)).unwrap();
+    Spi::run("SET pgmer2.cache = on").unwrap();
 
-    let res = crate::mr_edgelist(None).unwrap();
+    let count = || Spi::get_one::<i64>("SELECT count(*) FROM mr_scores('U1')").unwrap();
 
-    let n = res.map(|x| {
-      let (ego, target, score) = unpack_edge(&x);
-      assert_eq!(ego,    "B1");
-      assert_eq!(target, "B2");
-      assert_eq!(score,  3.0);
-    }).count();
+    let before = count();
+    assert_eq!(count(), before);
 
-    assert_eq!(n, 1);
+    //  Writes clear the cache.
+    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(1.0), None).unwrap();
+    let _ = crate::mr_sync(Some(1000)).unwrap();
+    assert_eq!(count(), before.map(|x| x + 1));
+
+    Spi::run("RESET pgmer2.cache").unwrap();
   }
 
   #[pg_test]
-  fn node_score_context() {
-    let _ = crate::mr_reset().unwrap();
+  fn response_cache_key() {
+    Spi::run("SET pgmer2.cache = on").unwrap();
 
-    let _ = crate::mr_put_edge(Some("U1"), Some("U2"), Some(2.0), Some("X")).unwrap();
-    let _ = crate::mr_put_edge(Some("U1"), Some("U3"), Some(1.0), Some("X")).unwrap();
-    let _ = crate::mr_put_edge(Some("U3"), Some("U2"), Some(3.0), Some("X")).unwrap();
+    let payload  = b"request".to_vec();
+    let response = nng::Message::from(&b"response"[..]);
+
+    crate::cache::put(&payload, "tcp://127.0.0.1:10234", &response, crate::cache::generation());
+    assert!(crate::cache::get(&payload).is_some());
+
+    //  Reads through the primary don't invalidate the cache.
     let _ = crate::mr_sync(Some(1000)).unwrap();
+    assert!(crate::cache::get(&payload).is_some());
 
-    let res = crate::mr_node_score(Some("U1"), Some("U2"), Some("X")).unwrap();
+    //  Entries of other endpoints are not used.
+    Spi::run("SET pgmer2.service_url = 'tcp://127.0.0.1:10298'").unwrap();
+    assert!(crate::cache::get(&payload).is_none());
 
-    let n = res.map(|x| {
-      let (ego, target, score) = unpack_edge(&x);
-      assert_eq!(ego, "U1");
-      assert_eq!(target, "U2");
-      assert!(score > 0.3);
-      assert!(score < 0.45);
-    }).count();
+    Spi::run("RESET pgmer2.service_url").unwrap();
+    assert!(crate::cache::get(&payload).is_some());
 
-    assert_eq!(n, 1);
+    //  Writes do.
+    let _ = crate::mr_reset().unwrap();
+    assert!(crate::cache::get(&payload).is_none());
+
+    Spi::run("RESET pgmer2.cache").unwrap();
+  }
+
+  #[pg_test]
+  fn cache_status() {
+    //  The shared cache needs shared_preload_libraries, so in tests it
+    //  is disabled and the counters stay at zero.
+    let slots = Spi::get_one::<i64>("SELECT slots FROM mr_cache_status").unwrap();
+    assert_eq!(slots, Some(0));
+
+    let _ = crate::mr_scores(Some("U1"), None, None, None, None, None, None, None, None, None, None, None, None);
+
+    let lookups = Spi::get_one::<i64>("SELECT hits + misses FROM mr_cache_status").unwrap();
+    assert_eq!(lookups, Some(0));
   }
 
   #[pg_test]
@@ -1025,7 +4519,8 @@ mod tests {
       Some("U"),
       Some(10.0), None,
       Some(0.0), None,
-      None, None
+      None, None,
+      None, None, None
     ).unwrap());
 
     assert_eq!(res.len(), 3);
@@ -1070,7 +4565,8 @@ mod tests {
       Some("U"),
       Some(10.0), None,
       Some(0.0), None,
-      None, None
+      None, None,
+      None, None, None
     ).unwrap());
 
     assert_eq!(res.len(), 3);
@@ -1115,7 +4611,8 @@ mod tests {
       Some("U"),
       None, None,
       None, None,
-      None, None
+      None, None,
+      None, None, None
     ).unwrap());
 
     assert_eq!(res.len(), 3);
@@ -1344,6 +4841,6 @@ pub mod pg_test {
   }
 
   pub fn postgresql_conf_options() -> Vec<&'static str> {
-    vec![]
+    vec!["pgmer2.outbox_database = 'pgrx_tests'"]
   }
 }
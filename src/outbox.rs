@@ -0,0 +1,269 @@
+use pgrx::*;
+use pgrx::bgworkers::*;
+use std::cell::Cell;
+use std::time::Duration;
+use core::result::Result;
+
+use crate::error::MrError;
+use crate::{
+  write_request,
+  recv_timeout_msec,
+  text_arg,
+  int8_arg,
+  OUTBOX_DATABASE_GUC,
+  OUTBOX_NAPTIME_GUC,
+};
+
+//  Durable queue of mutating commands. With pgmer2.outbox enabled,
+//  writes are inserted here in the caller's transaction, and the
+//  background worker sends them to the service in order, retrying
+//  while the service is unavailable. Delivery is at least once.
+
+extension_sql!(r#"
+CREATE TABLE IF NOT EXISTS mr_outbox (
+  id          bigserial   PRIMARY KEY,
+  payload     bytea       NOT NULL,
+  created_at  timestamptz NOT NULL DEFAULT clock_timestamp(),
+  attempts    integer     NOT NULL DEFAULT 0,
+  failed      boolean     NOT NULL DEFAULT false,
+  last_error  text,
+  xid         xid8        NOT NULL DEFAULT pg_current_xact_id(),
+  commit_seq  bigint
+);
+
+ALTER TABLE mr_outbox ADD COLUMN IF NOT EXISTS xid        xid8 NOT NULL DEFAULT pg_current_xact_id();
+ALTER TABLE mr_outbox ADD COLUMN IF NOT EXISTS commit_seq bigint;
+
+CREATE SEQUENCE IF NOT EXISTS mr_outbox_commit_seq;
+
+CREATE INDEX IF NOT EXISTS mr_outbox_uncommitted ON mr_outbox (xid) WHERE commit_seq IS NULL;
+
+CREATE OR REPLACE VIEW mr_outbox_status AS SELECT
+  count(*) FILTER (WHERE NOT failed)                              AS queued,
+  count(*) FILTER (WHERE failed)                                  AS failed,
+  clock_timestamp() - min(created_at) FILTER (WHERE NOT failed)   AS lag,
+  coalesce(max(attempts) FILTER (WHERE NOT failed), 0)            AS attempts,
+  (SELECT last_error FROM mr_outbox
+    WHERE last_error IS NOT NULL
+    ORDER BY id DESC
+    LIMIT 1)                                                      AS last_error
+  FROM mr_outbox;
+"#,
+  name = "outbox",
+);
+
+//  Commands sent per worker transaction.
+const BATCH : i64 = 1000;
+
+//  Upper bound for the retry delay.
+const MAX_BACKOFF_MSEC : u64 = 60000;
+
+//  Advisory lock key of the commit sequence.
+const COMMIT_LOCK : i64 = 0x7067_6d65_7232_6f62;
+
+thread_local! {
+  //  Whether the current transaction wrote to the outbox.
+  static ENQUEUED : Cell<bool> = Cell::new(false);
+}
+
+//  Database the worker drains, see mr_outbox_worker.
+
+fn database() -> String {
+  match OUTBOX_DATABASE_GUC.get() {
+    Some(s) if !s.to_bytes().is_empty() => s.to_string_lossy().into_owned(),
+    _                                    => "postgres".to_string(),
+  }
+}
+
+pub fn enqueue(payloads : Vec<Vec<u8>>) -> Result<usize, MrError> {
+  let n = payloads.len();
+
+  if n == 0 {
+    return Ok(0);
+  }
+
+  //  Rows in other databases would never be sent.
+  let current : Option<String> = Spi::get_one("SELECT current_database()::text")?;
+  let current = current.unwrap_or_default();
+
+  if current != database() {
+    return Err(MrError::InvalidArgument(format!(
+      "the outbox worker drains database {}, not {}, set pgmer2.outbox_database",
+      database(), current
+    )));
+  }
+
+  ENQUEUED.with(|x| x.set(true));
+
+  for payload in payloads {
+    Spi::run_with_args(
+      "INSERT INTO mr_outbox (payload) VALUES ($1)",
+      Some(vec![(PgBuiltInOids::BYTEAOID.oid(), payload.into_datum())]),
+    )?;
+  }
+
+  return Ok(n);
+}
+
+//  Ids follow the order rows were inserted in, not the order their
+//  transactions commit in. So before commit, the rows of a transaction
+//  get a number from the commit sequence, under a lock held until the
+//  commit is done. Transactions wait for each other only while they
+//  commit, and a row is visible to the worker only after all rows
+//  with lower numbers are.
+
+pub fn pre_commit() -> Result<(), MrError> {
+  if !ENQUEUED.with(|x| x.replace(false)) {
+    return Ok(());
+  }
+
+  Spi::run_with_args(
+    "SELECT pg_advisory_xact_lock($1)",
+    Some(vec![int8_arg(COMMIT_LOCK)]),
+  )?;
+
+  Spi::run(
+    "UPDATE mr_outbox
+        SET commit_seq = (SELECT nextval('mr_outbox_commit_seq'))
+      WHERE xid = pg_current_xact_id() AND commit_seq IS NULL",
+  )?;
+
+  return Ok(());
+}
+
+pub fn enqueued() -> bool {
+  ENQUEUED.with(|x| x.get())
+}
+
+pub fn reset() {
+  ENQUEUED.with(|x| x.set(false));
+}
+
+fn record_failure(id : i64, failed : bool, error : &str) -> Result<(), MrError> {
+  Spi::run_with_args(
+    "UPDATE mr_outbox
+        SET attempts   = attempts + 1,
+            failed     = $2,
+            last_error = $3
+      WHERE id = $1",
+    Some(vec![
+      int8_arg(id),
+      (PgBuiltInOids::BOOLOID.oid(), failed.into_datum()),
+      text_arg(error),
+    ]),
+  )?;
+  return Ok(());
+}
+
+//  Sends queued commands in order. Stops at the first connection error,
+//  so later commands are never applied before earlier ones. Commands
+//  rejected by the service are marked failed and skipped.
+//  Returns the number of commands processed.
+
+pub fn drain() -> Result<i64, MrError> {
+  let rows = Spi::connect(|client| {
+    let mut rows = vec![];
+
+    let table = client.select(
+      "SELECT id, payload
+         FROM mr_outbox
+        WHERE NOT failed AND commit_seq IS NOT NULL
+        ORDER BY commit_seq, id
+        LIMIT $1",
+      None,
+      Some(vec![int8_arg(BATCH)]),
+    )?;
+
+    for row in table {
+      let id      : Option<i64>     = row.get(1)?;
+      let payload : Option<Vec<u8>> = row.get(2)?;
+
+      match (id, payload) {
+        (Some(id), Some(payload)) => rows.push((id, payload)),
+        _                         => {},
+      }
+    }
+
+    Ok::<_, MrError>(rows)
+  })?;
+
+  let mut processed = 0;
+
+  for (id, payload) in rows {
+    match write_request::<()>(payload, Some(recv_timeout_msec())) {
+      Ok(_) => {
+        Spi::run_with_args(
+          "DELETE FROM mr_outbox WHERE id = $1",
+          Some(vec![int8_arg(id)]),
+        )?;
+      },
+
+      Err(MrError::Service { message, .. }) => {
+        warning!("pgmer2: outbox command {} rejected: {}", id, message);
+        record_failure(id, true, &message)?;
+      },
+
+      Err(e) => {
+        record_failure(id, false, &e.to_string())?;
+        return Err(e);
+      },
+    }
+
+    processed += 1;
+  }
+
+  return Ok(processed);
+}
+
+pub fn register_worker() {
+  BackgroundWorkerBuilder::new("pgmer2 outbox")
+    .set_function("mr_outbox_worker")
+    .set_library("pgmer2")
+    .enable_spi_access()
+    .set_restart_time(Some(Duration::from_secs(10)))
+    .load();
+}
+
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn mr_outbox_worker(_arg : pg_sys::Datum) {
+  BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+
+  let database = database();
+
+  BackgroundWorker::connect_worker_to_spi(Some(&database), None);
+
+  log!("pgmer2: outbox worker started for database {}", database);
+
+  let naptime   = || Duration::from_millis(OUTBOX_NAPTIME_GUC.get() as u64);
+  let mut delay = naptime();
+  let mut retry = 0;
+
+  while BackgroundWorker::wait_latch(Some(delay)) {
+    if BackgroundWorker::sighup_received() {
+      unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext_PGC_SIGHUP) };
+    }
+
+    match BackgroundWorker::transaction(drain) {
+      //  More may be queued, continue right away.
+      Ok(n) if n == BATCH => {
+        retry = 0;
+        delay = Duration::ZERO;
+      },
+
+      Ok(_) => {
+        retry = 0;
+        delay = naptime();
+      },
+
+      Err(e) => {
+        retry = (retry + 1).min(16);
+        let backoff = (naptime().as_millis() as u64).saturating_mul(1 << retry);
+        delay = Duration::from_millis(backoff.min(MAX_BACKOFF_MSEC));
+        log!("pgmer2: outbox delivery failed, retrying in {} ms: {}", delay.as_millis(), e);
+      },
+    }
+  }
+
+  log!("pgmer2: outbox worker stopped");
+}
@@ -0,0 +1,349 @@
+use pgrx::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{SHARED_CACHE_SIZE_GUC, SHARED_CACHE_TTL_GUC};
+
+//  Read responses shared by all backends, in shared memory of
+//  pgmer2.shared_cache_size. Requires pgmer2 in shared_preload_libraries.
+//
+//  The cache is split into sets of WAYS fixed-size slots. A key goes to
+//  the set given by its hash, and replaces the least recently used slot
+//  of that set. Invalidation bumps the generation, which makes all
+//  existing entries stale at once.
+
+const NAME : &[u8] = b"pgmer2 shared cache\0";
+
+fn name() -> *const std::ffi::c_char {
+  NAME.as_ptr() as *const std::ffi::c_char
+}
+
+//  Bytes per slot, including the slot header. Larger responses are
+//  not cached.
+const SLOT_BYTES : usize = 8192;
+const SLOT_DATA  : usize = SLOT_BYTES - 6 * 8;
+
+const WAYS : usize = 8;
+
+#[repr(C)]
+struct Header {
+  lock          : *mut pg_sys::LWLock,
+  slots         : usize,
+  generation    : AtomicU64,
+  clock         : AtomicU64,
+  hits          : AtomicU64,
+  misses        : AtomicU64,
+  evictions     : AtomicU64,
+  invalidations : AtomicU64,
+}
+
+//  Slot data: key, then URL, then response. Slots are written under
+//  the exclusive lock, and read under the shared lock. last_used is
+//  bumped by readers, so it is atomic.
+#[repr(C)]
+struct Slot {
+  generation : u64,
+  last_used  : AtomicU64,
+  created_ms : u64,
+  hash       : u64,
+  key_len    : u32,
+  url_len    : u32,
+  value_len  : u32,
+  used       : u32,
+  data       : [u8; SLOT_DATA],
+}
+
+static mut HEADER : *mut Header = std::ptr::null_mut();
+
+#[cfg(not(feature = "pg14"))]
+static mut PREV_SHMEM_REQUEST_HOOK : pg_sys::shmem_request_hook_type = None;
+static mut PREV_SHMEM_STARTUP_HOOK : pg_sys::shmem_startup_hook_type = None;
+
+fn size_bytes() -> usize {
+  SHARED_CACHE_SIZE_GUC.get().max(0) as usize * 1024
+}
+
+fn slot_count() -> usize {
+  size_bytes() / SLOT_BYTES
+}
+
+fn shmem_size() -> usize {
+  std::mem::size_of::<Header>() + slot_count() * std::mem::size_of::<Slot>()
+}
+
+//  Called from _PG_init while shared_preload_libraries are loaded.
+
+pub fn init() {
+  //  Shared memory can only be requested while preloading. Loaded later,
+  //  the request would be ignored, and startup would find no memory.
+  if !unsafe { pg_sys::process_shared_preload_libraries_in_progress } {
+    return;
+  }
+
+  if slot_count() == 0 {
+    return;
+  }
+
+  unsafe {
+    #[cfg(feature = "pg14")]
+    request_shmem();
+
+    #[cfg(not(feature = "pg14"))]
+    {
+      PREV_SHMEM_REQUEST_HOOK = pg_sys::shmem_request_hook;
+      pg_sys::shmem_request_hook = Some(shmem_request);
+    }
+
+    PREV_SHMEM_STARTUP_HOOK = pg_sys::shmem_startup_hook;
+    pg_sys::shmem_startup_hook = Some(shmem_startup);
+  }
+}
+
+unsafe fn request_shmem() {
+  pg_sys::RequestAddinShmemSpace(shmem_size());
+  pg_sys::RequestNamedLWLockTranche(name(), 1);
+}
+
+#[cfg(not(feature = "pg14"))]
+#[pg_guard]
+unsafe extern "C" fn shmem_request() {
+  if let Some(prev) = PREV_SHMEM_REQUEST_HOOK {
+    prev();
+  }
+  request_shmem();
+}
+
+#[pg_guard]
+unsafe extern "C" fn shmem_startup() {
+  if let Some(prev) = PREV_SHMEM_STARTUP_HOOK {
+    prev();
+  }
+
+  //  As in other modules, AddinShmemInitLock guards initialization, so
+  //  backends attaching at the same time don't see a half-initialized
+  //  header.
+  let init_lock = addin_shmem_init_lock();
+  pg_sys::LWLockAcquire(init_lock, pg_sys::LWLockMode_LW_EXCLUSIVE);
+
+  let mut found = false;
+  let header = pg_sys::ShmemInitStruct(name(), shmem_size(), &mut found) as *mut Header;
+
+  if !found {
+    std::ptr::write_bytes(header as *mut u8, 0, shmem_size());
+    (*header).lock  = &mut (*pg_sys::GetNamedLWLockTranche(name())).lock;
+    (*header).slots = slot_count();
+  }
+
+  HEADER = header;
+
+  pg_sys::LWLockRelease(init_lock);
+}
+
+//  AddinShmemInitLock is a macro in lwlocknames.h, so it has no binding.
+//  It expands to the lock at index 21 of MainLWLockArray, fixed by
+//  lwlocknames.txt in all supported versions. pgrx's own shared memory
+//  support takes it the same way.
+
+unsafe fn addin_shmem_init_lock() -> *mut pg_sys::LWLock {
+  &mut (*pg_sys::MainLWLockArray.add(21)).lock
+}
+
+fn header() -> Option<&'static Header> {
+  unsafe { HEADER.as_ref() }
+}
+
+pub fn active() -> bool {
+  header().is_some()
+}
+
+unsafe fn slot(header : &Header, n : usize) -> *mut Slot {
+  let first = (header as *const Header).add(1) as *mut Slot;
+  first.add(n)
+}
+
+fn hash(key : &[u8]) -> u64 {
+  let mut hasher = DefaultHasher::new();
+  hasher.write(key);
+  hasher.finish()
+}
+
+fn now_ms() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|x| x.as_millis() as u64)
+    .unwrap_or(0)
+}
+
+//  Slots of the set a hash belongs to.
+
+fn set_of(header : &Header, hash : u64) -> std::ops::Range<usize> {
+  let sets  = (header.slots / WAYS).max(1);
+  let first = (hash % sets as u64) as usize * WAYS;
+  first..(first + WAYS).min(header.slots)
+}
+
+struct Lock(*mut pg_sys::LWLock);
+
+impl Lock {
+  fn exclusive(header : &Header) -> Lock {
+    unsafe { pg_sys::LWLockAcquire(header.lock, pg_sys::LWLockMode_LW_EXCLUSIVE) };
+    Lock(header.lock)
+  }
+
+  fn shared(header : &Header) -> Lock {
+    unsafe { pg_sys::LWLockAcquire(header.lock, pg_sys::LWLockMode_LW_SHARED) };
+    Lock(header.lock)
+  }
+}
+
+impl Drop for Lock {
+  fn drop(&mut self) {
+    unsafe { pg_sys::LWLockRelease(self.0) };
+  }
+}
+
+//  Returns the cached URL and response for a key.
+
+pub fn get(key : &[u8]) -> Option<(String, Vec<u8>)> {
+  let header = header()?;
+
+  let h          = hash(key);
+  let generation = header.generation.load(Ordering::Acquire);
+  let ttl        = SHARED_CACHE_TTL_GUC.get().max(1) as u64;
+  let now        = now_ms();
+
+  let _lock = Lock::shared(header);
+
+  for n in set_of(header, h) {
+    let slot = unsafe { &*slot(header, n) };
+
+    if slot.used == 0 || slot.hash != h || slot.generation != generation {
+      continue;
+    }
+    if now.saturating_sub(slot.created_ms) > ttl {
+      continue;
+    }
+
+    let key_len   = slot.key_len as usize;
+    let url_len   = slot.url_len as usize;
+    let value_len = slot.value_len as usize;
+
+    if &slot.data[..key_len] != key {
+      continue;
+    }
+
+    slot.last_used.store(header.clock.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+    header.hits.fetch_add(1, Ordering::Relaxed);
+
+    let url   = String::from_utf8_lossy(&slot.data[key_len..key_len + url_len]).into_owned();
+    let value = slot.data[key_len + url_len..key_len + url_len + value_len].to_vec();
+
+    return Some((url, value));
+  }
+
+  header.misses.fetch_add(1, Ordering::Relaxed);
+  return None;
+}
+
+//  Current generation. Read it before sending the request, and pass it
+//  to put, so a response that raced with a write is not stored.
+
+pub fn generation() -> u64 {
+  header()
+    .map(|x| x.generation.load(Ordering::Acquire))
+    .unwrap_or(0)
+}
+
+pub fn put(key : &[u8], url : &str, value : &[u8], generation : u64) {
+  let header = match header() {
+    Some(x) => x,
+    None    => return,
+  };
+
+  if key.len() + url.len() + value.len() > SLOT_DATA {
+    return;
+  }
+
+  if generation != header.generation.load(Ordering::Acquire) {
+    return;
+  }
+
+  let h     = hash(key);
+  let _lock = Lock::exclusive(header);
+
+  //  Prefer a free or stale slot, then the least recently used one.
+  let victim =
+    set_of(header, h)
+      .min_by_key(|&n| {
+        let slot = unsafe { &*slot(header, n) };
+        match (slot.used, slot.generation == generation) {
+          (0, _)     => (0, 0),
+          (_, false) => (1, 0),
+          (_, true)  => (2, slot.last_used.load(Ordering::Relaxed)),
+        }
+      });
+
+  let n = match victim {
+    Some(x) => x,
+    None    => return,
+  };
+
+  let slot = unsafe { &mut *slot(header, n) };
+
+  if slot.used != 0 && slot.generation == generation {
+    header.evictions.fetch_add(1, Ordering::Relaxed);
+  }
+
+  let (k, u, v) = (key.len(), url.len(), value.len());
+
+  slot.data[..k]             .copy_from_slice(key);
+  slot.data[k..k + u]        .copy_from_slice(url.as_bytes());
+  slot.data[k + u..k + u + v].copy_from_slice(value);
+
+  slot.generation = generation;
+  slot.last_used.store(header.clock.fetch_add(1, Ordering::Relaxed), Ordering::Relaxed);
+  slot.created_ms = now_ms();
+  slot.hash       = h;
+  slot.key_len    = k as u32;
+  slot.url_len    = u as u32;
+  slot.value_len  = v as u32;
+  slot.used       = 1;
+}
+
+//  Makes all cached entries stale.
+
+pub fn invalidate() {
+  if let Some(header) = header() {
+    header.generation.fetch_add(1, Ordering::AcqRel);
+    header.invalidations.fetch_add(1, Ordering::Relaxed);
+  }
+}
+
+//  (hits, misses, evictions, invalidations, entries, slots)
+
+pub fn stats() -> Option<(u64, u64, u64, u64, u64, u64)> {
+  let header = header()?;
+
+  let generation = header.generation.load(Ordering::Acquire);
+
+  let entries = {
+    let _lock = Lock::shared(header);
+    (0..header.slots)
+      .filter(|&n| {
+        let slot = unsafe { &*slot(header, n) };
+        slot.used != 0 && slot.generation == generation
+      })
+      .count() as u64
+  };
+
+  return Some((
+    header.hits.load(Ordering::Relaxed),
+    header.misses.load(Ordering::Relaxed),
+    header.evictions.load(Ordering::Relaxed),
+    header.invalidations.load(Ordering::Relaxed),
+    entries,
+    header.slots as u64,
+  ));
+}